@@ -0,0 +1,544 @@
+//! SMTP authentication (RFC 4954)
+//!
+//! The client authenticates after the final `EHLO`, using the first mechanism
+//! from `ClientAuth::mechanisms` that the server also advertises.
+//!
+//! Mechanisms implement `SaslMechanism`. Built-in implementations exist for
+//! `PLAIN`, `LOGIN`, `CRAM-MD5`, `SCRAM-SHA-256` and `XOAUTH2`, and any other
+//! mechanism can be added to `ClientAuth::mechanisms`.
+
+use base64;
+use ehlo::{EhloCapabilities};
+use hmac::{Hmac, Mac};
+use md5::{Md5};
+use rand::{self, Rng};
+use response::{Response};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc};
+
+
+/// A SASL mechanism
+///
+/// The mechanism holds whatever it needs to authenticate, and starts a fresh
+/// `SaslExchange` for every connection.
+pub trait SaslMechanism {
+    /// The mechanism name, as advertised and sent in `AUTH`
+    fn name(&self) -> &str;
+
+    /// Start a new exchange
+    fn start(&self) -> Box<SaslExchange>;
+}
+
+/// The client side of a single SASL exchange
+pub trait SaslExchange {
+    /// The initial response to send along with `AUTH`, if any
+    fn initial_response(&mut self) -> Option<Vec<u8>>;
+
+    /// Respond to a challenge from the server
+    ///
+    /// Returning an error cancels the exchange.
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, ()>;
+
+    /// Check the exchange once the server reports success
+    ///
+    /// `additional` is any data the server sent along with its `235`
+    /// response, or empty. Returning an error fails authentication even
+    /// though the server accepted it.
+    fn complete(&mut self, additional: &[u8]) -> Result<(), ()> {
+        let _ = additional;
+        Ok(())
+    }
+}
+
+
+/// Credentials to authenticate with
+#[derive(PartialEq,Eq,Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: String, password: String) -> Self {
+        Credentials {
+            username: username,
+            password: password,
+        }
+    }
+}
+
+
+/// The `PLAIN` mechanism (RFC 4616)
+pub struct Plain(pub Credentials);
+
+impl SaslMechanism for Plain {
+    fn name(&self) -> &str {
+        "PLAIN"
+    }
+
+    fn start(&self) -> Box<SaslExchange> {
+        let mut response = Vec::new();
+        response.push(0);
+        response.extend_from_slice(self.0.username.as_bytes());
+        response.push(0);
+        response.extend_from_slice(self.0.password.as_bytes());
+        Box::new(OneShot(Some(response)))
+    }
+}
+
+
+/// The `LOGIN` mechanism, a widely supported non-standard mechanism
+pub struct Login(pub Credentials);
+
+impl SaslMechanism for Login {
+    fn name(&self) -> &str {
+        "LOGIN"
+    }
+
+    fn start(&self) -> Box<SaslExchange> {
+        Box::new(LoginExchange {
+            credentials: self.0.clone(),
+            step: 0,
+        })
+    }
+}
+
+struct LoginExchange {
+    credentials: Credentials,
+    step: usize,
+}
+
+impl SaslExchange for LoginExchange {
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn respond(&mut self, _: &[u8]) -> Result<Vec<u8>, ()> {
+        // The server prompts for `Username:` and `Password:`, in that order.
+        self.step += 1;
+        match self.step {
+            1 => Ok(self.credentials.username.as_bytes().to_vec()),
+            2 => Ok(self.credentials.password.as_bytes().to_vec()),
+            _ => Err(()),
+        }
+    }
+}
+
+
+/// The `CRAM-MD5` mechanism (RFC 2195)
+pub struct CramMd5(pub Credentials);
+
+impl SaslMechanism for CramMd5 {
+    fn name(&self) -> &str {
+        "CRAM-MD5"
+    }
+
+    fn start(&self) -> Box<SaslExchange> {
+        Box::new(CramMd5Exchange(Some(self.0.clone())))
+    }
+}
+
+struct CramMd5Exchange(Option<Credentials>);
+
+impl SaslExchange for CramMd5Exchange {
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, ()> {
+        let credentials = self.0.take().ok_or(())?;
+        let digest = hmac_md5(credentials.password.as_bytes(), challenge);
+        let mut response = credentials.username;
+        response.push(' ');
+        for byte in digest {
+            response.push_str(&format!("{:02x}", byte));
+        }
+        Ok(response.into_bytes())
+    }
+}
+
+
+/// The `SCRAM-SHA-256` mechanism (RFC 7677), without channel binding
+pub struct ScramSha256(pub Credentials);
+
+impl SaslMechanism for ScramSha256 {
+    fn name(&self) -> &str {
+        "SCRAM-SHA-256"
+    }
+
+    fn start(&self) -> Box<SaslExchange> {
+        let mut nonce = [0u8; 18];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Box::new(ScramExchange::new(self.0.clone(), base64::encode(&nonce)))
+    }
+}
+
+struct ScramExchange {
+    credentials: Credentials,
+    nonce: String,
+    step: usize,
+    client_first_bare: String,
+    server_key: Vec<u8>,
+    auth_message: String,
+    verified: bool,
+}
+
+impl ScramExchange {
+    fn new(credentials: Credentials, nonce: String) -> Self {
+        let client_first_bare = format!("n={},r={}",
+            scram_escape(&credentials.username), nonce);
+        ScramExchange {
+            credentials: credentials,
+            nonce: nonce,
+            step: 0,
+            client_first_bare: client_first_bare,
+            server_key: vec![],
+            auth_message: String::new(),
+            verified: false,
+        }
+    }
+
+    fn client_final(&mut self, server_first: &str) -> Result<Vec<u8>, ()> {
+        let (mut nonce, mut salt, mut iterations) = (None, None, None);
+        for attr in server_first.split(',') {
+            match attr.get(..2) {
+                Some("r=") => nonce = Some(&attr[2..]),
+                Some("s=") => salt = base64::decode(&attr[2..]).ok(),
+                Some("i=") => iterations = attr[2..].parse::<u32>().ok(),
+                // Mandatory extensions are not supported.
+                Some("m=") => return Err(()),
+                _ => {},
+            }
+        }
+        let nonce = nonce.ok_or(())?;
+        let mut salt = salt.ok_or(())?;
+        let iterations = iterations.ok_or(())?;
+        if iterations == 0 || nonce.len() <= self.nonce.len() ||
+                !nonce.starts_with(self.nonce.as_str()) {
+            return Err(());
+        }
+
+        // Hi() is PBKDF2 with HMAC-SHA-256, and needs only a single block.
+        let password = self.credentials.password.as_bytes();
+        salt.extend_from_slice(&[0, 0, 0, 1]);
+        let mut block = hmac_sha256(password, &salt);
+        let mut salted_password = block.clone();
+        for _ in 1..iterations {
+            block = hmac_sha256(password, &block);
+            for (out, byte) in salted_password.iter_mut().zip(block.iter()) {
+                *out ^= *byte;
+            }
+        }
+
+        // `biws` is the encoded GS2 header, `n,,`.
+        let client_final_bare = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}",
+            self.client_first_bare, server_first, client_final_bare);
+
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let proof = client_key.iter()
+            .zip(client_signature.iter())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+
+        self.server_key = hmac_sha256(&salted_password, b"Server Key");
+        self.auth_message = auth_message;
+
+        Ok(format!("{},p={}", client_final_bare, base64::encode(&proof)).into_bytes())
+    }
+
+    /// Check the server signature in the server-final message.
+    fn verify(&self, server_final: &[u8]) -> bool {
+        if self.server_key.is_empty() || !server_final.starts_with(b"v=") {
+            return false;
+        }
+        let signature = match base64::decode(&server_final[2..]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.server_key)
+            .expect("HMAC accepts any key length");
+        mac.input(self.auth_message.as_bytes());
+        mac.verify(&signature).is_ok()
+    }
+}
+
+impl SaslExchange for ScramExchange {
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        Some(format!("n,,{}", self.client_first_bare).into_bytes())
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, ()> {
+        self.step += 1;
+        match self.step {
+            1 => {
+                let challenge = String::from_utf8(challenge.to_vec()).map_err(|_| ())?;
+                self.client_final(&challenge)
+            },
+            // Verify the server knows the password too, before it accepts.
+            2 if self.verify(challenge) => {
+                self.verified = true;
+                Ok(vec![])
+            },
+            _ => Err(()),
+        }
+    }
+
+    fn complete(&mut self, additional: &[u8]) -> Result<(), ()> {
+        // The server-final message may also arrive with the `235` response.
+        if self.verified || self.verify(additional) {
+            self.verified = true;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+fn scram_escape(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+
+/// The `XOAUTH2` mechanism, using an OAuth 2.0 bearer token
+pub struct XOAuth2 {
+    pub username: String,
+    pub token: String,
+}
+
+impl XOAuth2 {
+    pub fn new(username: String, token: String) -> Self {
+        XOAuth2 {
+            username: username,
+            token: token,
+        }
+    }
+}
+
+impl SaslMechanism for XOAuth2 {
+    fn name(&self) -> &str {
+        "XOAUTH2"
+    }
+
+    fn start(&self) -> Box<SaslExchange> {
+        let response = format!("user={}\x01auth=Bearer {}\x01\x01",
+            self.username, self.token);
+        Box::new(OneShot(Some(response.into_bytes())))
+    }
+}
+
+
+/// An exchange consisting of only an initial response
+///
+/// A challenge, such as the `XOAUTH2` error details, is answered with an
+/// empty response, after which the server reports failure.
+struct OneShot(Option<Vec<u8>>);
+
+impl SaslExchange for OneShot {
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        self.0.take()
+    }
+
+    fn respond(&mut self, _: &[u8]) -> Result<Vec<u8>, ()> {
+        Ok(vec![])
+    }
+}
+
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Md5>::new_varkey(key).expect("HMAC accepts any key length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+
+/// Authentication parameters for the client
+#[derive(Clone)]
+pub struct ClientAuth {
+    /// Mechanisms to use, in order of preference
+    pub mechanisms: Vec<Arc<SaslMechanism + Send + Sync>>,
+    /// Whether credentials may be sent over an insecure connection
+    pub allow_insecure: bool,
+}
+
+impl ClientAuth {
+    /// Authenticate with a password using any built-in mechanism, over
+    /// secure connections only
+    pub fn new(credentials: Credentials) -> Self {
+        ClientAuth {
+            mechanisms: vec![
+                Arc::new(ScramSha256(credentials.clone())),
+                Arc::new(CramMd5(credentials.clone())),
+                Arc::new(Plain(credentials.clone())),
+                Arc::new(Login(credentials)),
+            ],
+            allow_insecure: false,
+        }
+    }
+
+    /// Authenticate using only the given mechanism, over secure connections
+    /// only
+    pub fn with_mechanism<M>(mechanism: M) -> Self
+            where M: SaslMechanism + Send + Sync + 'static {
+        ClientAuth {
+            mechanisms: vec![Arc::new(mechanism)],
+            allow_insecure: false,
+        }
+    }
+
+    /// Pick the preferred mechanism the server advertised.
+    pub fn select_mechanism(&self, capabilities: &EhloCapabilities)
+            -> Option<Arc<SaslMechanism + Send + Sync>> {
+        self.mechanisms.iter()
+            .find(|mechanism| capabilities.supports_auth(mechanism.name()))
+            .cloned()
+    }
+}
+
+
+/// Encode the initial response sent along with `AUTH`
+pub fn encode_initial(data: &[u8]) -> String {
+    if data.is_empty() {
+        // An empty initial response is sent as a single `=`.
+        "=".to_string()
+    } else {
+        base64::encode(data)
+    }
+}
+
+/// Encode a response line
+pub fn encode(data: &[u8]) -> String {
+    base64::encode(data)
+}
+
+/// Decode a `334` challenge
+pub fn decode_challenge(response: &Response) -> Result<Vec<u8>, ()> {
+    let text = response.text.get(0).map(|s| s.trim()).unwrap_or("");
+    base64::decode(text).map_err(|_| ())
+}
+
+/// Decode additional data sent with a `235` response
+///
+/// The data is a single base64 word after the optional enhanced status code.
+/// Anything else is human-readable text, and decodes to empty data.
+///
+/// A single word of text that happens to be valid base64, like `Accepted`,
+/// can't be told apart from data and decodes to arbitrary bytes, so a
+/// mechanism must not trust `additional` data it can't verify.
+pub fn decode_success(response: &Response) -> Vec<u8> {
+    let text = response.text.get(0).map(|s| s.as_str()).unwrap_or("");
+    let mut words = text.split_whitespace();
+    if response.enhanced_code().is_some() {
+        words.next();
+    }
+    match (words.next(), words.next()) {
+        (Some(word), None) => base64::decode(word).unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ehlo::{EhloCapabilities};
+    use auth::{ClientAuth, CramMd5, Credentials, Login, Plain, SaslMechanism, ScramExchange,
+               SaslExchange, XOAuth2, decode_challenge, decode_success, encode, encode_initial};
+
+    #[test]
+    fn test() {
+        let credentials = Credentials::new("john".to_string(), "secret".to_string());
+        let auth = ClientAuth::new(credentials.clone());
+
+        for (input, expect) in vec![
+            ("250-mx.test\r\n250-AUTH LOGIN PLAIN\r\n250 8BITMIME\r\n", Some("PLAIN")),
+            ("250-mx.test\r\n250 AUTH=LOGIN\r\n", Some("LOGIN")),
+            ("250-mx.test\r\n250 AUTH PLAIN CRAM-MD5\r\n", Some("CRAM-MD5")),
+            ("250-mx.test\r\n250 AUTH CRAM-MD5 SCRAM-SHA-256\r\n", Some("SCRAM-SHA-256")),
+            ("250-mx.test\r\n250 AUTH GSSAPI\r\n", None),
+            ("250-AUTH PLAIN\r\n250 8BITMIME\r\n", None),
+            ("250 mx.test\r\n", None),
+        ] {
+            let caps = EhloCapabilities::from_response(&input.parse().unwrap());
+            let selected = auth.select_mechanism(&caps);
+            assert_eq!(selected.as_ref().map(|mechanism| mechanism.name()), expect);
+        }
+
+        let initial = Plain(credentials.clone()).start().initial_response().unwrap();
+        assert_eq!(encode_initial(&initial), "AGpvaG4Ac2VjcmV0");
+        assert_eq!(encode_initial(b""), "=");
+        assert_eq!(encode(b""), "");
+
+        let challenge = decode_challenge(&"334 VXNlcm5hbWU6\r\n".parse().unwrap()).unwrap();
+        assert_eq!(challenge, b"Username:".to_vec());
+        let mut exchange = Login(credentials.clone()).start();
+        assert_eq!(exchange.initial_response(), None);
+        assert_eq!(exchange.respond(&challenge), Ok(b"john".to_vec()));
+        assert_eq!(exchange.respond(b"Password:"), Ok(b"secret".to_vec()));
+        assert_eq!(exchange.respond(b""), Err(()));
+
+        // Example from RFC 2195.
+        let credentials = Credentials::new("tim".to_string(), "tanstaaftanstaaf".to_string());
+        let mut exchange = CramMd5(credentials).start();
+        assert_eq!(exchange.initial_response(), None);
+        assert_eq!(exchange.respond(b"<1896.697170952@postoffice.reston.mci.net>"),
+                   Ok(b"tim b913a602c7eda7a495b4e6e7334d3890".to_vec()));
+
+        // Example from RFC 7677.
+        let credentials = Credentials::new("user".to_string(), "pencil".to_string());
+        let server_first = b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                             s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let mut exchange = ScramExchange::new(credentials.clone(), "rOprNGfwEbeRWgbNEkqO".to_string());
+        assert_eq!(exchange.initial_response(), Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO".to_vec()));
+        assert_eq!(exchange.respond(server_first),
+                   Ok(b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                        p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=".to_vec()));
+        let server_final = b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+        assert_eq!(exchange.respond(server_final), Ok(vec![]));
+        assert_eq!(exchange.complete(b""), Ok(()));
+
+        let mut exchange = ScramExchange::new(credentials.clone(), "rOprNGfwEbeRWgbNEkqO".to_string());
+        exchange.respond(server_first).unwrap();
+        assert_eq!(exchange.respond(b"v=AAAA"), Err(()));
+
+        // Success without the server signature is a failure.
+        let mut exchange = ScramExchange::new(credentials.clone(), "rOprNGfwEbeRWgbNEkqO".to_string());
+        exchange.respond(server_first).unwrap();
+        assert_eq!(exchange.complete(b""), Err(()));
+        assert_eq!(exchange.complete(b"v=AAAA"), Err(()));
+        assert_eq!(exchange.complete(server_final), Ok(()));
+        let mut exchange = ScramExchange::new(credentials.clone(), "rOprNGfwEbeRWgbNEkqO".to_string());
+        assert_eq!(exchange.complete(server_final), Err(()));
+
+        for (input, expect) in vec![
+            ("235 2.7.0 dj1zaWc=\r\n", b"v=sig".to_vec()),
+            ("235 dj1zaWc=\r\n", b"v=sig".to_vec()),
+            ("235 2.7.0 Authentication successful\r\n", vec![]),
+            // Text that is also valid base64 is taken as data.
+            ("235 2.7.0 Accepted\r\n", vec![0x01, 0xc7, 0x1e, 0xa6, 0xd7, 0x9d]),
+            ("235 2.7.0 Accepted!\r\n", vec![]),
+            ("235 2.7.0\r\n", vec![]),
+            ("235 OK!\r\n", vec![]),
+        ] {
+            assert_eq!(decode_success(&input.parse().unwrap()), expect);
+        }
+        let mut exchange = ScramExchange::new(credentials, "other".to_string());
+        assert_eq!(exchange.respond(server_first), Err(()));
+        assert_eq!(Plain(Credentials::new("a".to_string(), "b".to_string())).start().complete(b""),
+                   Ok(()));
+
+        let auth = ClientAuth::with_mechanism(XOAuth2::new("john".to_string(), "token".to_string()));
+        let caps = EhloCapabilities::from_response(
+            &"250-mx.test\r\n250 AUTH PLAIN XOAUTH2\r\n".parse().unwrap());
+        let mechanism = auth.select_mechanism(&caps);
+        let mut exchange = mechanism.unwrap().start();
+        assert_eq!(exchange.initial_response(), Some(b"user=john\x01auth=Bearer token\x01\x01".to_vec()));
+        assert_eq!(exchange.respond(b"{\"status\":\"401\"}"), Ok(vec![]));
+    }
+}
@@ -88,25 +88,43 @@
 //! }
 //! ```
 
-use futures::{future, Future, Stream, Sink, Poll};
+use auth::{self, ClientAuth};
+use ehlo::{EhloCapabilities};
+use error::{SmtpError};
+use futures::{future, Future, Stream, Sink, Poll, StartSend};
+use futures::future::{Loop};
 use native_tls::{Result as TlsResult, TlsConnector};
 use nom::{IResult as NomResult};
 use request::{ClientId, Request};
 use response::{Response, Severity};
+use std::collections::{VecDeque};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Read, Write};
-use std::sync::{Arc};
+use std::net::{SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration};
 use bytes::{BufMut, BytesMut};
+use tokio_core::net::{TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Encoder, Decoder, Framed};
-use tokio_proto::{TcpClient as TokioTcpClient};
-use tokio_proto::streaming::{Body};
-use tokio_proto::streaming::pipeline::{ClientProto as TokioClientProto, Frame, StreamingPipeline};
+use tokio_proto::{BindClient, TcpClient as TokioTcpClient};
+use tokio_proto::streaming::{Body, Message};
+use tokio_proto::streaming::pipeline::{ClientProto as TokioClientProto, Frame, StreamingPipeline, Transport};
+use tokio_proto::util::client_proxy::{ClientProxy};
+use tokio_service::{Service};
 use tokio_tls::{TlsConnectorExt, TlsStream};
+use util::{BodyEncoder, Redacted, with_timeout};
 
 // FIXME: `<T: Io + 'static>`, but E0122
 pub type ClientTransport<T> = Framed<ClientIo<T>, ClientCodec>;
 pub type ClientBindTransport<T> = Box<Future<Item = ClientTransport<T>, Error = IoError>>;
-pub type TcpClient = TokioTcpClient<StreamingPipeline<Body<Vec<u8>, IoError>>, ClientProto>;
+type ClientHandshake<T> = Box<Future<Item = (ConnectionInfo, ClientTransport<T>), Error = IoError>>;
+pub type TcpClient = TokioTcpClient<StreamingPipeline<ClientBody>, ClientProto>;
+pub type ClientBody = Body<Vec<u8>, IoError>;
+type ClientRequest = Message<Request, ClientBody>;
+type ClientResponse = Message<Response, Body<(), IoError>>;
+pub type ClientConnect = Box<Future<Item = (ConnectionInfo, ClientService), Error = SmtpError>>;
 
 
 /// Parameters to use for secure clients
@@ -137,18 +155,177 @@ pub struct ClientParams {
     pub id: ClientId,
     /// Whether to use a secure connection, and how
     pub security: ClientSecurity,
+    /// Whether to authenticate, and how
+    pub auth: Option<ClientAuth>,
+    /// Whether to require the server to announce ESMTP in its greeting, and
+    /// to accept `EHLO`
+    ///
+    /// If not set, `HELO` is sent when the server rejects `EHLO`. `HELO`
+    /// only takes a domain, so there is no fallback if `id` is an address
+    /// literal, and the `EHLO` rejection is reported instead.
+    pub require_esmtp: bool,
+    /// How long to wait for the server
+    pub timeouts: ClientTimeouts,
+    /// Whether to convert bare `CR` and `LF` in message bodies to `CRLF`
+    ///
+    /// If not set, line endings are sent as is, and only lines ending in
+    /// `CRLF` are dot-stuffed.
+    pub normalize_line_endings: bool,
+}
+
+
+/// How long to wait for the server in each phase of a connection
+///
+/// The defaults are the minimums from RFC 5321, section 4.5.3.2. Connecting
+/// and the TLS handshake are not covered by the RFC, and default to the same
+/// value as the greeting.
+///
+/// Timeouts are applied when connecting through `ClientProto::connect` or
+/// `ClientProto::connect_io`, and by `Mailer`. A `TcpClient` has no access to
+/// the event loop, and can't apply them.
+///
+/// Once the service is bound, the server is only waited for once a request
+/// is sent, and pipelined requests wait for the previous response first.
+#[derive(Copy,Clone,Debug)]
+pub struct ClientTimeouts {
+    /// Establishing the TCP connection
+    pub connect: Duration,
+    /// Waiting for the greeting
+    pub greeting: Duration,
+    /// Waiting for the response to a command
+    pub command: Duration,
+    /// Performing the TLS handshake
+    pub tls: Duration,
+    /// Waiting for the `354` response to `DATA`
+    pub data_initiation: Duration,
+    /// Writing a block of the message to the connection
+    pub data_block: Duration,
+    /// Waiting for the response to the complete message
+    pub data_termination: Duration,
+}
+
+impl Default for ClientTimeouts {
+    fn default() -> Self {
+        ClientTimeouts {
+            connect: Duration::from_secs(5 * 60),
+            greeting: Duration::from_secs(5 * 60),
+            command: Duration::from_secs(5 * 60),
+            tls: Duration::from_secs(5 * 60),
+            data_initiation: Duration::from_secs(2 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_termination: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+
+/// How TLS was applied to an established connection
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+pub enum TlsNegotiation {
+    /// Insecure connection
+    None,
+    /// Negotiated using `STARTTLS`
+    StartTls,
+    /// TLS without negotiation
+    Immediate,
+}
+
+
+/// Information about an established connection
+#[derive(Clone,Debug)]
+pub struct ConnectionInfo {
+    /// The server opening response
+    pub greeting: Response,
+    /// The response to the final `EHLO`, or `HELO` if the server doesn't
+    /// support ESMTP
+    pub ehlo_response: Response,
+    /// The extensions advertised in the final `EHLO` response
+    ///
+    /// If `HELO` was used, only the domain is set.
+    pub capabilities: EhloCapabilities,
+    /// How TLS was applied
+    pub tls: TlsNegotiation,
+    /// Whether the server accepted `EHLO`
+    pub esmtp: bool,
+}
+
+impl ConnectionInfo {
+    fn new(greeting: Response, ehlo_response: Response, tls: TlsNegotiation, esmtp: bool) -> Self {
+        let mut capabilities = EhloCapabilities::from_response(&ehlo_response);
+        if !esmtp {
+            capabilities = EhloCapabilities {
+                domain: capabilities.domain,
+                ..EhloCapabilities::default()
+            };
+        }
+        ConnectionInfo {
+            greeting: greeting,
+            ehlo_response: ehlo_response,
+            capabilities: capabilities,
+            tls: tls,
+            esmtp: esmtp,
+        }
+    }
+
+    /// The domain the server identified itself with in the `EHLO` response
+    pub fn domain(&self) -> &str {
+        &self.capabilities.domain
+    }
+
+    /// Tells if the connection is secure
+    pub fn is_secure(&self) -> bool {
+        self.tls != TlsNegotiation::None
+    }
 }
 
 
 /// The codec used to encode client requests and decode server responses
-#[derive(Default)]
+///
+/// The `354` intermediate response to `DATA` is dropped, but other
+/// intermediate responses (e.g. `334` during `AUTH`) are passed on.
+///
+/// The body of `BDAT` is sent as is, and must have the declared size. Body
+/// streams should not fail, because tokio-proto can't handle it. To abort a
+/// message, see `ClientService::abort_body`.
 pub struct ClientCodec {
-    escape_count: u8,
+    body: BodyEncoder,
+    // Whether the current body belongs to `BDAT`, and is sent as is.
+    raw_body: bool,
+    expect_greeting: bool,
+    // Shared with the transport of a bound service, which applies timeouts.
+    progress: Arc<Mutex<Progress>>,
 }
 
 impl ClientCodec {
     pub fn new() -> Self {
-        ClientCodec::default()
+        ClientCodec {
+            body: BodyEncoder::new(true, true),
+            raw_body: false,
+            expect_greeting: false,
+            progress: Arc::new(Mutex::new(Progress::default())),
+        }
+    }
+
+    /// Create a codec that expects the server greeting as the first response.
+    pub fn with_greeting() -> Self {
+        ClientCodec {
+            expect_greeting: true,
+            ..ClientCodec::new()
+        }
+    }
+
+    /// Set whether to convert bare `CR` and `LF` in message bodies to `CRLF`.
+    ///
+    /// By default, line endings are converted.
+    pub fn set_normalize_line_endings(mut self, normalize: bool) -> Self {
+        self.body = BodyEncoder::new(normalize, true);
+        self
+    }
+}
+
+impl Default for ClientCodec {
+    fn default() -> Self {
+        ClientCodec::new()
     }
 }
 
@@ -157,43 +334,50 @@ impl Encoder for ClientCodec {
     type Error = IoError;
 
     fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> IoResult<()> {
-        debug!("C: {:?}", &frame);
         match frame {
-            Frame::Message { message, .. } => {
-                buf.put_slice(message.to_string().as_bytes());
+            Frame::Message { ref message, .. } => debug!("C: {:?}", Redacted(message)),
+            ref frame => debug!("C: {:?}", frame),
+        }
+        match frame {
+            Frame::Message { message, body } => {
+                let is_data = message == Request::Data;
+                if is_data {
+                    self.body.start();
+                }
+                let (raw_body, last) = match message {
+                    Request::Bdat { last, .. } => (true, last),
+                    _ => (false, false),
+                };
+                self.raw_body = raw_body;
+                self.progress.lock().unwrap().in_flight.push_back(InFlight {
+                    is_data: is_data,
+                    is_final: is_data || last,
+                    started: false,
+                    sent: !body,
+                });
+                let line = message.to_string();
+                buf.reserve(line.len());
+                buf.put_slice(line.as_bytes());
             },
             Frame::Body { chunk: Some(chunk) } => {
-                // Escape lines starting with a '.'
-                // FIXME: additional encoding for non-ASCII?
-                let mut start = 0;
-                for (idx, byte) in chunk.iter().enumerate() {
-                    match self.escape_count {
-                        0 => self.escape_count = if *byte == b'\r' { 1 } else { 0 },
-                        1 => self.escape_count = if *byte == b'\n' { 2 } else { 0 },
-                        2 => self.escape_count = if *byte == b'.'  { 3 } else { 0 },
-                        _ => unreachable!(),
-                    }
-                    if self.escape_count == 3 {
-                        self.escape_count = 0;
-                        buf.put_slice(&chunk[start..idx]);
-                        buf.put_slice(b".");
-                        start = idx;
-                    }
+                if self.raw_body {
+                    buf.reserve(chunk.len());
+                    buf.put_slice(&chunk);
+                } else {
+                    // 8-bit data is sent as is. Declaring it with the
+                    // `BODY` parameter of `MAIL` is up to the caller.
+                    self.body.encode(&chunk, buf);
                 }
-                buf.put_slice(&chunk[start..]);
             },
             Frame::Body { chunk: None } => {
-                match self.escape_count {
-                    0 => buf.put_slice(b"\r\n.\r\n"),
-                    1 => buf.put_slice(b"\n.\r\n"),
-                    2 => buf.put_slice(b".\r\n"),
-                    _ => unreachable!(),
+                if !self.raw_body {
+                    self.body.finish(buf);
+                }
+                if let Some(request) = self.progress.lock().unwrap().in_flight.back_mut() {
+                    request.sent = true;
                 }
-                self.escape_count = 0;
-            },
-            Frame::Error { error } => {
-                panic!("unimplemented error handling: {:?}", error);
             },
+            Frame::Error { error } => return Err(error),
         }
         Ok(())
     }
@@ -211,10 +395,22 @@ impl Decoder for ClientCodec {
                 // Calculate how much data to drain.
                 bytes = buf.len() - rest.len();
 
-                // Drop intermediate messages (e.g. DATA 354)
-                if res.code.severity == Severity::PositiveIntermediate {
+                // Drop the intermediate response to DATA (354). The final
+                // response follows after the body.
+                let mut progress = self.progress.lock().unwrap();
+                let is_data = progress.in_flight.front().map_or(false, |request| request.is_data);
+                if is_data && res.code.severity == Severity::PositiveIntermediate {
+                    if let Some(request) = progress.in_flight.front_mut() {
+                        request.started = true;
+                    }
                     Ok(None)
                 } else {
+                    if self.expect_greeting {
+                        self.expect_greeting = false;
+                    } else {
+                        progress.in_flight.pop_front();
+                        progress.responses += 1;
+                    }
                     let frame = Frame::Message { message: res, body: false };
                     debug!("S: {:?}", &frame);
                     Ok(Some(frame))
@@ -224,7 +420,7 @@ impl Decoder for ClientCodec {
                 Ok(None)
             },
             NomResult::Error(_) => {
-                Err(IoError::new(IoErrorKind::InvalidData, "malformed response"))
+                Err(SmtpError::MalformedResponse.into())
             },
         };
 
@@ -240,9 +436,201 @@ impl Decoder for ClientCodec {
 
         res
     }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> IoResult<Option<Self::Item>> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() && self.progress.lock().unwrap().in_flight.is_empty() => Ok(None),
+            // Fail instead of ending the stream, so requests awaiting a
+            // response don't wait forever.
+            None => Err(IoError::new(IoErrorKind::UnexpectedEof, "connection closed by server")),
+        }
+    }
+}
+
+
+/// A request awaiting a response
+#[derive(Copy,Clone,Debug)]
+struct InFlight {
+    // Whether the request is `DATA`, which gets a `354` response first.
+    is_data: bool,
+    // Whether the response is to the complete message.
+    is_final: bool,
+    // Whether the `354` response to `DATA` arrived.
+    started: bool,
+    // Whether the request and its body were written.
+    sent: bool,
+}
+
+/// What the server is expected to send next
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+enum Wait {
+    Command,
+    DataInitiation,
+    DataTermination,
+}
+
+/// The requests awaiting a response, in the order they were sent
+#[derive(Default,Debug)]
+struct Progress {
+    in_flight: VecDeque<InFlight>,
+    // The number of responses so far, to tell successive requests apart.
+    responses: u64,
+}
+
+impl Progress {
+    /// What the oldest request waits for, if anything. There is nothing to
+    /// wait for while its body is still being sent.
+    fn wait(&self) -> Option<(u64, Wait)> {
+        let request = self.in_flight.front()?;
+        let wait = if request.is_data && !request.started {
+            Wait::DataInitiation
+        } else if !request.sent {
+            return None;
+        } else if request.is_final {
+            Wait::DataTermination
+        } else {
+            Wait::Command
+        };
+        Some((self.responses, wait))
+    }
 }
 
 
+/// The transport of a bound service, which fails if the server doesn't keep
+/// up
+///
+/// The response to the oldest request is waited for with the timeout for its
+/// phase, starting when it is sent or the previous response arrives. Writes
+/// that make no progress fail after the data block timeout.
+struct TimedTransport<T> {
+    inner: ClientTransport<T>,
+    progress: Arc<Mutex<Progress>>,
+    timeouts: ClientTimeouts,
+    handle: Handle,
+    response_timer: Option<((u64, Wait), Timeout)>,
+    write_timer: Option<Timeout>,
+    timed_out: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl<T> TimedTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    fn new(transport: ClientTransport<T>, timeouts: ClientTimeouts, handle: Handle,
+           timed_out: Arc<AtomicBool>, aborted: Arc<AtomicBool>) -> Self {
+        // Take the progress from the codec, and put the codec back.
+        let (parts, codec) = transport.into_parts_and_codec();
+        let progress = codec.progress.clone();
+        TimedTransport {
+            inner: Framed::from_parts(parts, codec),
+            progress: progress,
+            timeouts: timeouts,
+            handle: handle,
+            response_timer: None,
+            write_timer: None,
+            timed_out: timed_out,
+            aborted: aborted,
+        }
+    }
+
+    /// Start the timer for what the server is expected to send, and fail
+    /// if it expired.
+    fn poll_response_timer(&mut self) -> IoResult<()> {
+        let wait = self.progress.lock().unwrap().wait();
+        if self.response_timer.as_ref().map(|&(current, _)| current) != wait {
+            self.response_timer = match wait {
+                Some((key, kind)) => {
+                    let duration = match kind {
+                        Wait::Command => self.timeouts.command,
+                        Wait::DataInitiation => self.timeouts.data_initiation,
+                        Wait::DataTermination => self.timeouts.data_termination,
+                    };
+                    Some(((key, kind), Timeout::new(duration, &self.handle)?))
+                },
+                None => None,
+            };
+        }
+        let expired = match self.response_timer {
+            Some((_, ref mut timer)) => timer.poll()?.is_ready(),
+            None => false,
+        };
+        self.check_expired(expired)
+    }
+
+    fn check_expired(&self, expired: bool) -> IoResult<()> {
+        if expired {
+            self.timed_out.store(true, Ordering::SeqCst);
+            Err(SmtpError::Timeout.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T> Stream for TimedTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type Item = Frame<Response, (), IoError>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, IoError> {
+        // A response that already arrived wins over the timer, which is
+        // started for the next one once there is nothing left to read.
+        let result = self.inner.poll()?;
+        if result.is_not_ready() {
+            self.poll_response_timer()?;
+        }
+        Ok(result)
+    }
+}
+
+impl<T> Sink for TimedTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type SinkItem = Frame<Request, Vec<u8>, IoError>;
+    type SinkError = IoError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, IoError> {
+        // Close the connection instead of terminating an aborted body.
+        if let Frame::Body { chunk: None } = item {
+            if self.aborted.load(Ordering::SeqCst) {
+                return Err(IoError::new(IoErrorKind::Other, "message body aborted"));
+            }
+        }
+        let result = self.inner.start_send(item)?;
+        if result.is_ready() {
+            self.write_timer = None;
+        }
+        Ok(result)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), IoError> {
+        let result = self.inner.poll_complete()?;
+        if result.is_ready() {
+            self.write_timer = None;
+        } else if self.write_timer.is_none() {
+            self.write_timer = Some(Timeout::new(self.timeouts.data_block, &self.handle)?);
+        }
+        let expired = match self.write_timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false,
+        };
+        self.check_expired(expired)?;
+        self.poll_response_timer()?;
+        Ok(result)
+    }
+
+    fn close(&mut self) -> Poll<(), IoError> {
+        self.inner.close()
+    }
+}
+
+impl<T> Transport for TimedTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{}
+
+
 /// An `Io` implementation that wraps a secure or insecure transport into a
 /// single type.
 pub enum ClientIo<T> {
@@ -311,152 +699,296 @@ where T: AsyncWrite + 'static, TlsStream<T>: AsyncWrite + Write
 /// Implements an SMTP client using a streaming pipeline protocol.
 pub struct ClientProto(pub Arc<ClientParams>);
 
-// FIXME: Return opening response.
-fn handshake<T>(io: ClientIo<T>, params: Arc<ClientParams>, await_opening: bool) ->
-    Box<Future<Item = (Response, Framed<ClientIo<T>, ClientCodec>), Error = IoError>>
+/// Receive a single response, failing if the connection is closed, or the
+/// response doesn't arrive in time.
+fn receive<T>(stream: ClientTransport<T>, closed_msg: &'static str,
+              timeout: Duration, handle: Option<&Handle>)
+    -> Box<Future<Item = (Response, ClientTransport<T>), Error = IoError>>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    with_timeout(stream.into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(response, stream)| {
+            match response {
+                Some(Frame::Message { message, .. }) => future::ok((message, stream)),
+                _ => future::err(IoError::new(IoErrorKind::InvalidData, closed_msg)),
+            }
+        }), timeout, handle)
+}
+
+/// Start the codec and send `EHLO`, then receive the response.
+///
+/// If no greeting is given, the server opening is awaited first. If the
+/// server rejects `EHLO`, this falls back to `HELO`, unless ESMTP is
+/// required.
+fn handshake<T>(io: ClientIo<T>, params: Arc<ClientParams>, greeting: Option<Response>,
+                tls: TlsNegotiation, handle: Option<Handle>) -> ClientHandshake<T>
 where T: AsyncRead + AsyncWrite + 'static
 {
+    let require_esmtp = params.require_esmtp;
+    let timeouts = params.timeouts;
+    let greeting_handle = handle.clone();
+    let helo_handle = handle.clone();
+    let codec = if greeting.is_none() { ClientCodec::with_greeting() } else { ClientCodec::new() };
     Box::new(
         // Start codec.
-        io.framed(ClientCodec::new())
+        io.framed(codec.set_normalize_line_endings(params.normalize_line_endings))
         // Send EHLO.
             .send(Request::Ehlo(params.id.clone()).into())
             .and_then(move |stream| {
                 // Receive server opening.
-                if await_opening {
-                    future::Either::A(stream.into_future()
-                        .map_err(|(err, _)| err)
-                        .and_then(|(response, stream)| {
-                            // Fail if closed.
-                            let response = match response {
-                                Some(Frame::Message { message, .. }) => message,
-                                _ => return future::err(IoError::new(
-                                    IoErrorKind::InvalidData, "connection closed before handshake")),
-                            };
-                            
-                            // Ensure it likes us, and supports ESMTP.
-                            let esmtp = response.text.get(0)
-                                .and_then(|line| line.split_whitespace().nth(1));
-                            if !response.code.severity.is_positive() || esmtp != Some("ESMTP") {
-                                return future::err(IoError::new(
-                                    IoErrorKind::InvalidData, "invalid handshake"));
-                            }
-                            
-                            future::ok(stream)
-                        }))
-                } else {
-                    future::Either::B(future::ok(stream))
+                if let Some(greeting) = greeting {
+                    return future::Either::B(future::ok((greeting, stream)));
                 }
-            })
-        // Receive EHLO response.
-            .and_then(|stream| {
-                stream.into_future()
-                    .map_err(|(err, _)| err)
-                    .and_then(|(response, stream)| {
-                        // Fail if closed.
-                        let response = match response {
-                            Some(Frame::Message { message, .. }) => message,
-                            _ => return future::err(IoError::new(
-                                IoErrorKind::InvalidData, "connection closed during handshake")),
-                        };
+                future::Either::A(receive(stream, "connection closed before handshake",
+                                          timeouts.greeting, greeting_handle.as_ref())
+                    .and_then(move |(response, stream)| {
+                        // Ensure it likes us, and supports ESMTP if required.
+                        let esmtp = response.text.get(0)
+                            .and_then(|line| line.split_whitespace().nth(1));
+                        if !response.code.severity.is_positive() ||
+                                (require_esmtp && esmtp != Some("ESMTP")) {
+                            return future::err(SmtpError::Handshake(response).into());
+                        }
                         
                         future::ok((response, stream))
+                    }))
+            })
+        // Receive EHLO response.
+            .and_then(move |(greeting, stream)| {
+                receive(stream, "connection closed during handshake",
+                        timeouts.command, handle.as_ref())
+                    .map(|(response, stream)| (greeting, response, stream))
+            })
+            .and_then(move |(greeting, response, stream)| {
+                if response.code.severity.is_positive() {
+                    let info = ConnectionInfo::new(greeting, response, tls, true);
+                    return future::Either::A(future::ok((info, stream)));
+                }
+
+                let is_domain = match params.id {
+                    ClientId::Domain(_) => true,
+                    _ => false,
+                };
+                if require_esmtp || !is_domain ||
+                        response.code.severity != Severity::PermanentNegativeCompletion {
+                    return future::Either::A(future::err(SmtpError::Handshake(response).into()));
+                }
+
+                // Retry with HELO. No extensions are available in this mode.
+                future::Either::B(stream.send(Request::Helo(params.id.clone()).into())
+                    .and_then(move |stream| {
+                        receive(stream, "connection closed during handshake",
+                                timeouts.command, helo_handle.as_ref())
                     })
+                    .and_then(move |(response, stream)| {
+                        if !response.code.severity.is_positive() {
+                            return future::err(SmtpError::Handshake(response).into());
+                        }
+
+                        future::ok((ConnectionInfo::new(greeting, response, tls, false), stream))
+                    }))
             })
     )
 }
 
+/// Authenticate if requested, after the final `EHLO`.
+fn authenticate<T>(info: ConnectionInfo, stream: ClientTransport<T>, params: Arc<ClientParams>,
+                   handle: Option<Handle>) -> ClientHandshake<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    let (name, mut exchange) = {
+        let client_auth = match params.auth {
+            Some(ref client_auth) => client_auth,
+            None => return Box::new(future::ok((info, stream))),
+        };
+
+        if !info.is_secure() && !client_auth.allow_insecure {
+            return Box::new(future::err(SmtpError::AuthInsecure.into()));
+        }
+
+        let mechanism = match client_auth.select_mechanism(&info.capabilities) {
+            Some(mechanism) => mechanism,
+            None => return Box::new(future::err(SmtpError::AuthUnsupported.into())),
+        };
+        (mechanism.name().to_string(), mechanism.start())
+    };
+
+    let timeout = params.timeouts.command;
+    let request = Request::Auth {
+        mechanism: name,
+        initial_response: exchange.initial_response().map(|data| auth::encode_initial(&data)),
+    };
+    Box::new(stream.send(request.into())
+        // Answer challenges until the server accepts or rejects us.
+        .and_then(move |stream| {
+            future::loop_fn((stream, exchange), move |(stream, mut exchange)| {
+                receive(stream, "connection closed during auth", timeout, handle.as_ref())
+                    .and_then(move |(response, stream)| {
+                        match response.code.severity {
+                            Severity::PositiveCompletion => {
+                                match exchange.complete(&auth::decode_success(&response)) {
+                                    Ok(()) => future::Either::A(future::ok(Loop::Break(stream))),
+                                    Err(()) => future::Either::A(future::err(
+                                        SmtpError::AuthFailed(response).into())),
+                                }
+                            },
+                            Severity::PositiveIntermediate => {
+                                let answer = auth::decode_challenge(&response)
+                                    .and_then(|challenge| exchange.respond(&challenge));
+                                // Cancel the exchange if we can't answer. The
+                                // server then responds with an error.
+                                let line = match answer {
+                                    Ok(data) => auth::encode(&data),
+                                    Err(()) => "*".to_string(),
+                                };
+                                future::Either::B(stream.send(Request::AuthResponse(line).into())
+                                    .map(move |stream| Loop::Continue((stream, exchange))))
+                            },
+                            _ => {
+                                future::Either::A(future::err(SmtpError::AuthFailed(response).into()))
+                            },
+                        }
+                    })
+            })
+        })
+        .map(move |stream| (info, stream)))
+}
+
 impl ClientProto {
-    fn connect<T>(io: T, params: Arc<ClientParams>) -> ClientBindTransport<T>
+    /// Connect to the server at `addr`, and bind a service to the connection
+    /// after the handshake.
+    ///
+    /// Unlike connecting through a `TcpClient`, this reports handshake
+    /// errors, and returns information about the connection along with the
+    /// service.
+    pub fn connect(&self, addr: &SocketAddr, handle: &Handle) -> ClientConnect {
+        let params = self.0.clone();
+        let handle = handle.clone();
+        let connect = TcpStream::connect(addr, &handle).map_err(SmtpError::Io);
+        Box::new(with_timeout(connect, params.timeouts.connect, Some(&handle))
+            .and_then(move |io| ClientProto(params).connect_io(io, &handle)))
+    }
+
+    /// Perform the handshake on an existing connection, and bind a service
+    /// to it.
+    pub fn connect_io<T>(&self, io: T, handle: &Handle) -> ClientConnect
+    where T: AsyncRead + AsyncWrite + 'static
+    {
+        let handle = handle.clone();
+        let timeouts = self.0.timeouts;
+        Box::new(Self::establish(io, self.0.clone(), Some(handle.clone()))
+            .map(move |(info, stream)| {
+                let timed_out = Arc::new(AtomicBool::new(false));
+                let aborted = Arc::new(AtomicBool::new(false));
+                let proto = BoundProto {
+                    timeouts: timeouts,
+                    handle: handle.clone(),
+                    timed_out: timed_out.clone(),
+                    aborted: aborted.clone(),
+                };
+                let proxy = BindClient::<StreamingPipeline<ClientBody>, _>
+                    ::bind_client(&proto, &handle, stream);
+                let service = ClientService {
+                    proxy: proxy,
+                    timed_out: timed_out,
+                    aborted: aborted,
+                };
+                (info, service)
+            })
+            .map_err(SmtpError::from))
+    }
+
+    /// Perform the handshake. Timeouts are only applied if a handle is given.
+    fn establish<T>(io: T, params: Arc<ClientParams>, handle: Option<Handle>) -> ClientHandshake<T>
     where T: AsyncRead + AsyncWrite + 'static
     {
-        match params.security {
+        let f = match params.security {
             ClientSecurity::None => {
-                Self::connect_plain(io, params)
+                Self::connect_plain(io, params.clone(), handle.clone())
             },
             ClientSecurity::Optional(_) | ClientSecurity::Required(_) => {
-                Self::connect_starttls(io, params)
+                Self::connect_starttls(io, params.clone(), handle.clone())
             },
             ClientSecurity::Immediate(_) => {
-                Self::connect_immediate_tls(io, params)
+                Self::connect_immediate_tls(io, params.clone(), handle.clone())
             },
-        }
+        };
+
+        // Authenticate after the final EHLO.
+        Box::new(f.and_then(move |(info, stream)| {
+            authenticate(info, stream, params, handle)
+        }))
     }
 
-    fn connect_plain<T>(io: T, params: Arc<ClientParams>) -> ClientBindTransport<T>
+    fn connect_plain<T>(io: T, params: Arc<ClientParams>, handle: Option<Handle>) -> ClientHandshake<T>
     where T: AsyncRead + AsyncWrite + 'static
     {
         // Perform the handshake.
-        Box::new(handshake(ClientIo::Plain(io), params, true)
-                 .map(|(_, stream)| stream))
+        handshake(ClientIo::Plain(io), params, None, TlsNegotiation::None, handle)
     }
 
-    fn connect_starttls<T>(io: T, params: Arc<ClientParams>) -> ClientBindTransport<T>
+    fn connect_starttls<T>(io: T, params: Arc<ClientParams>, handle: Option<Handle>) -> ClientHandshake<T>
     where T: AsyncRead + AsyncWrite + 'static
     {
         let is_required =
             if let ClientSecurity::Required(_) = params.security { true } else { false };
+        let timeouts = params.timeouts;
+        let starttls_handle = handle.clone();
         // Perform the handshake, and send STARTTLS.
-        Box::new(handshake(ClientIo::Plain(io), params.clone(), true)
-                 .and_then(move |(ehlo_response, stream)| {
-                     let is_supported = None != ehlo_response.text.iter()
-                         .find(|feature| feature.as_str() == "STARTTLS");
-                     
-                     if !is_supported {
+        Box::new(handshake(ClientIo::Plain(io), params.clone(), None, TlsNegotiation::None,
+                           handle.clone())
+                 .and_then(move |(info, stream)| {
+                     if !info.capabilities.starttls {
                          if is_required {
-                             return future::Either::B(future::Either::B(future::err(IoError::new(
-                                 IoErrorKind::InvalidData, "server doesn't support starttls"))));
+                             return future::Either::B(future::err(
+                                 SmtpError::StartTlsUnsupported.into()));
                          }
                          
-                         return future::Either::B(future::Either::A(future::ok(stream)));
+                         return future::Either::B(future::ok((info, stream)));
                      }
                      
                      future::Either::A(stream.send(Request::StartTls.into())
                      // Receive STARTTLS response.
-                         .and_then(|stream| {
-                             stream.into_future()
-                                 .map_err(|(err, _)| err)
+                         .and_then(move |stream| {
+                             receive(stream, "connection closed before starttls",
+                                     timeouts.command, starttls_handle.as_ref())
                          })
-                         .and_then(move |(response, stream)| {
-                             // Fail if closed.
-                             let response = match response {
-                                 Some(Frame::Message { message, .. }) => message,
-                                 None => return future::err(IoError::new(
-                                     IoErrorKind::InvalidData, "connection closed before starttls")),
-                                 _ => unreachable!(),
-                             };
-                             
-                             // Handle rejection.
-                             if !response.code.severity.is_positive() && is_required {
-                                 return future::err(IoError::new(
-                                     IoErrorKind::InvalidData, "starttls rejected"));
+                         .and_then(move |(response, stream)| -> ClientHandshake<T> {
+                             // Handle rejection, continuing without TLS if allowed.
+                             if !response.code.severity.is_positive() {
+                                 if is_required {
+                                     return Box::new(future::err(
+                                         SmtpError::rejected(Request::StartTls, response).into()));
+                                 }
+                                 return Box::new(future::ok((info, stream)));
                              }
                              
-                             future::ok(stream)
-                         })
-                         .and_then(move |stream| {
                              // Get the inner `Io` back, then start TLS on it.
                              // The block is to ensure the lifetime of `params.
-                             {
+                             Box::new({
                                  let io = stream.into_inner().unwrap_plain();
                                  let tls_params = match params.security {
                                      ClientSecurity::Optional(ref tls_params) |
                                      ClientSecurity::Required(ref tls_params) => tls_params,
                                      _ => panic!("bad params to connect_starttls"),
                                  };
-                                 tls_params.connector.connect_async(&tls_params.sni_domain, io)
-                                     .map_err(|err| IoError::new(IoErrorKind::Other, err))
+                                 let connect = tls_params.connector
+                                     .connect_async(&tls_params.sni_domain, io)
+                                     .map_err(|err| SmtpError::Tls(err).into());
+                                 with_timeout(connect, timeouts.tls, handle.as_ref())
                              }
                              .and_then(move |io| {
                                  // Re-do the handshake.
-                                 handshake(ClientIo::Secure(io), params, false)
-                                     .map(|(_, stream)| stream)
-                             })
+                                 handshake(ClientIo::Secure(io), params, Some(info.greeting),
+                                           TlsNegotiation::StartTls, handle)
+                             }))
                          }))
                  }))
     }
 
-    fn connect_immediate_tls<T>(io: T, params: Arc<ClientParams>) -> ClientBindTransport<T>
+    fn connect_immediate_tls<T>(io: T, params: Arc<ClientParams>, handle: Option<Handle>)
+        -> ClientHandshake<T>
     where T: AsyncRead + AsyncWrite + 'static
     {
         // Start TLS on the `Io` first.
@@ -466,13 +998,13 @@ impl ClientProto {
                 ClientSecurity::Immediate(ref tls_params) => tls_params,
                 _ => panic!("bad params to connect_immediate_tls"),
             };
-            tls_params.connector.connect_async(&tls_params.sni_domain, io)
-                .map_err(|err| IoError::new(IoErrorKind::Other, err))
+            let connect = tls_params.connector.connect_async(&tls_params.sni_domain, io)
+                .map_err(|err| SmtpError::Tls(err).into());
+            with_timeout(connect, params.timeouts.tls, handle.as_ref())
         }
             .and_then(move |io| {
                 // Perform the handshake.
-                handshake(ClientIo::Secure(io), params, true)
-                    .map(|(_, stream)| stream)
+                handshake(ClientIo::Secure(io), params, None, TlsNegotiation::Immediate, handle)
             }))
     }
 }
@@ -489,7 +1021,76 @@ where T: AsyncRead + AsyncWrite + 'static
     type BindTransport = ClientBindTransport<T>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Self::connect(io, self.0.clone())
+        Box::new(Self::establish(io, self.0.clone(), None)
+            .map(|(_, stream)| stream))
+    }
+}
+
+
+/// The protocol used to bind a service to an established connection
+struct BoundProto {
+    timeouts: ClientTimeouts,
+    handle: Handle,
+    timed_out: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl<T> TokioClientProto<ClientTransport<T>> for BoundProto
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type Request = Request;
+    type RequestBody = Vec<u8>;
+    type Response = Response;
+    type ResponseBody = ();
+    type Error = IoError;
+    type Transport = TimedTransport<T>;
+    type BindTransport = IoResult<Self::Transport>;
+
+    fn bind_transport(&self, io: ClientTransport<T>) -> Self::BindTransport {
+        Ok(TimedTransport::new(io, self.timeouts, self.handle.clone(),
+                               self.timed_out.clone(), self.aborted.clone()))
+    }
+}
+
+
+/// The service bound to an established connection
+///
+/// If the server doesn't respond in time, the connection is closed, and
+/// requests fail with `SmtpError::Timeout`.
+#[derive(Clone)]
+pub struct ClientService {
+    proxy: ClientProxy<ClientRequest, ClientResponse, IoError>,
+    timed_out: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl ClientService {
+    /// Abort the message whose body is being sent.
+    ///
+    /// The body should end right after. Instead of terminating the data, the
+    /// connection is then closed, so the server discards the message, and
+    /// all requests in flight fail.
+    pub fn abort_body(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Service for ClientService {
+    type Request = ClientRequest;
+    type Response = ClientResponse;
+    type Error = IoError;
+    type Future = Box<Future<Item = ClientResponse, Error = IoError>>;
+
+    fn call(&self, request: ClientRequest) -> Self::Future {
+        let timed_out = self.timed_out.clone();
+        Box::new(self.proxy.call(request).map_err(move |err| {
+            // The requests in flight are only told that the connection broke.
+            if timed_out.load(Ordering::SeqCst) {
+                SmtpError::Timeout.into()
+            } else {
+                err
+            }
+        }))
     }
 }
 
@@ -511,6 +1112,10 @@ impl Client {
         Self::with_params(ClientParams {
             security: ClientSecurity::None,
             id: id,
+            auth: None,
+            require_esmtp: false,
+            timeouts: ClientTimeouts::default(),
+            normalize_line_endings: true,
         })
     }
 
@@ -523,6 +1128,10 @@ impl Client {
                 sni_domain: sni_domain,
             }),
             id: id,
+            auth: None,
+            require_esmtp: false,
+            timeouts: ClientTimeouts::default(),
+            normalize_line_endings: true,
         }))
     }
 
@@ -535,6 +1144,10 @@ impl Client {
                 sni_domain: sni_domain,
             }),
             id: id,
+            auth: None,
+            require_esmtp: false,
+            timeouts: ClientTimeouts::default(),
+            normalize_line_endings: true,
         }))
     }
 
@@ -542,4 +1155,116 @@ impl Client {
     pub fn with_params(params: ClientParams) -> TcpClient {
         TokioTcpClient::new(ClientProto(Arc::new(params)))
     }
+
+    /// Connect using custom parameters, resulting in information about the
+    /// connection along with the service
+    pub fn connect(params: ClientParams, addr: &SocketAddr, handle: &Handle) -> ClientConnect {
+        ClientProto(Arc::new(params)).connect(addr, handle)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BytesMut};
+    use client::{ClientCodec, Wait};
+    use request::{Request};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use tokio_io::codec::{Decoder, Encoder};
+    use tokio_proto::streaming::pipeline::{Frame};
+
+    #[test]
+    fn test() {
+        for (input, normalize, expect) in vec![
+            (&b""[..], true, &b".\r\n"[..]),
+            (b".a\r\n", true, b"..a\r\n.\r\n"),
+            (b"a\r\n.b", true, b"a\r\n..b\r\n.\r\n"),
+            (b"a\nb\rc", true, b"a\r\nb\r\nc\r\n.\r\n"),
+            (b"a\r", true, b"a\r\n.\r\n"),
+            (b"\r.\n.", true, b"\r\n..\r\n..\r\n.\r\n"),
+            (b"a\r\r\n", true, b"a\r\n\r\n.\r\n"),
+            (b".", false, b"..\r\n.\r\n"),
+            (b"a\n.b", false, b"a\n..b\r\n.\r\n"),
+            (b"a\r.b", false, b"a\r..b\r\n.\r\n"),
+            (b"a\n", false, b"a\n\r\n.\r\n"),
+            (b"a\r", false, b"a\r\n.\r\n"),
+            (b".\r\n.", false, b"..\r\n..\r\n.\r\n"),
+            (b"a\rb", false, b"a\rb\r\n.\r\n"),
+            (b"a\r\r\n.", false, b"a\r\r\n..\r\n.\r\n"),
+        ] {
+            // Feed the input split at every combination of boundaries. The
+            // codec is reused, to test that every body starts afresh.
+            let mut codec = ClientCodec::new().set_normalize_line_endings(normalize);
+            let splits = input.len().saturating_sub(1);
+            for mask in 0..1 << splits {
+                let mut buf = BytesMut::new();
+                codec.encode(Frame::Message { message: Request::Data, body: true }, &mut buf).unwrap();
+                let mut start = 0;
+                for idx in 0..splits {
+                    if mask & (1 << idx) != 0 {
+                        let chunk = input[start..idx + 1].to_vec();
+                        codec.encode(Frame::Body { chunk: Some(chunk) }, &mut buf).unwrap();
+                        start = idx + 1;
+                    }
+                }
+                if start < input.len() {
+                    let chunk = input[start..].to_vec();
+                    codec.encode(Frame::Body { chunk: Some(chunk) }, &mut buf).unwrap();
+                }
+                codec.encode(Frame::Body { chunk: None }, &mut buf).unwrap();
+
+                assert_eq!(&buf[..6], b"DATA\r\n");
+                assert_eq!(&buf[6..], expect, "input {:?}, split mask {:b}", input, mask);
+            }
+        }
+
+        // The body of `BDAT` is sent as is.
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Frame::Message { message: Request::Bdat { size: 4, last: true }, body: true }, &mut buf).unwrap();
+        codec.encode(Frame::Body { chunk: Some(b".a\n\r".to_vec()) }, &mut buf).unwrap();
+        codec.encode(Frame::Body { chunk: None }, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"BDAT 4 LAST\r\n.a\n\r");
+
+        // Empty chunks are sent as nothing.
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Frame::Message { message: Request::Data, body: true }, &mut buf).unwrap();
+        codec.encode(Frame::Body { chunk: Some(b"partial".to_vec()) }, &mut buf).unwrap();
+        codec.encode(Frame::Body { chunk: Some(vec![]) }, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"DATA\r\npartial");
+
+        let error = IoError::new(IoErrorKind::Other, "failed");
+        assert!(codec.encode(Frame::Error { error: error }, &mut buf).is_err());
+
+        // Track what the server is expected to send next.
+        let mut codec = ClientCodec::new();
+        let mut buf = BytesMut::new();
+        let mut input = BytesMut::new();
+        let wait = |codec: &ClientCodec| codec.progress.lock().unwrap().wait();
+        assert_eq!(wait(&codec), None);
+        codec.encode(Frame::Message { message: Request::Noop, body: false }, &mut buf).unwrap();
+        codec.encode(Frame::Message { message: Request::Data, body: true }, &mut buf).unwrap();
+        assert_eq!(wait(&codec), Some((0, Wait::Command)));
+        input.extend_from_slice(b"250 OK\r\n");
+        assert!(codec.decode(&mut input).unwrap().is_some());
+        assert_eq!(wait(&codec), Some((1, Wait::DataInitiation)));
+        input.extend_from_slice(b"354 Go ahead\r\n");
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        assert_eq!(wait(&codec), None);
+        codec.encode(Frame::Body { chunk: None }, &mut buf).unwrap();
+        assert_eq!(wait(&codec), Some((1, Wait::DataTermination)));
+        input.extend_from_slice(b"250 OK\r\n");
+        assert!(codec.decode(&mut input).unwrap().is_some());
+        assert_eq!(wait(&codec), None);
+
+        for &(last, expect) in &[(false, Wait::Command), (true, Wait::DataTermination)] {
+            let mut codec = ClientCodec::new();
+            let request = Request::Bdat { size: 1, last: last };
+            codec.encode(Frame::Message { message: request, body: true }, &mut buf).unwrap();
+            assert_eq!(wait(&codec), None);
+            codec.encode(Frame::Body { chunk: None }, &mut buf).unwrap();
+            assert_eq!(wait(&codec), Some((0, expect)));
+        }
+    }
 }
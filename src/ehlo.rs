@@ -0,0 +1,179 @@
+//! Server capabilities, as advertised in the `EHLO` response
+//!
+//! The first line of the response is the server domain, followed by
+//! a greeting. Every other line is an extension keyword with optional
+//! arguments.
+
+use response::{Response};
+
+
+/// The extensions a server advertised in its `EHLO` response
+#[derive(PartialEq,Eq,Clone,Debug,Default)]
+pub struct EhloCapabilities {
+    /// The domain the server identified itself with
+    pub domain: String,
+    /// `SIZE` (RFC 1870), with the declared maximum message size
+    ///
+    /// A maximum of `0` means the server declared no fixed limit.
+    pub size: Option<u64>,
+    /// `STARTTLS` (RFC 3207)
+    pub starttls: bool,
+    /// `8BITMIME` (RFC 6152)
+    pub eight_bit_mime: bool,
+    /// `BINARYMIME` (RFC 3030)
+    pub binary_mime: bool,
+    /// `PIPELINING` (RFC 2920)
+    pub pipelining: bool,
+    /// `SMTPUTF8` (RFC 6531)
+    pub smtp_utf8: bool,
+    /// `CHUNKING` (RFC 3030)
+    pub chunking: bool,
+    /// `DSN` (RFC 3461)
+    pub dsn: bool,
+    /// `ENHANCEDSTATUSCODES` (RFC 2034)
+    pub enhanced_status_codes: bool,
+    /// SASL mechanisms from `AUTH` (RFC 4954)
+    pub auth: Vec<String>,
+    /// Any other keywords, uppercased, with their arguments
+    pub other: Vec<(String, Vec<String>)>,
+}
+
+impl EhloCapabilities {
+    /// Interpret an `EHLO` response
+    pub fn from_response(response: &Response) -> Self {
+        let mut res = EhloCapabilities::default();
+        let mut lines = response.text.iter();
+        if let Some(line) = lines.next() {
+            res.domain = line.split_whitespace().next().unwrap_or("").to_string();
+        }
+
+        for line in lines {
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword.to_ascii_uppercase(),
+                None => continue,
+            };
+            match keyword.as_str() {
+                "SIZE" => {
+                    res.size = Some(words.next().and_then(|s| s.parse().ok()).unwrap_or(0));
+                },
+                "STARTTLS" => res.starttls = true,
+                "8BITMIME" => res.eight_bit_mime = true,
+                "BINARYMIME" => res.binary_mime = true,
+                "PIPELINING" => res.pipelining = true,
+                "SMTPUTF8" => res.smtp_utf8 = true,
+                "CHUNKING" => res.chunking = true,
+                "DSN" => res.dsn = true,
+                "ENHANCEDSTATUSCODES" => res.enhanced_status_codes = true,
+                "AUTH" => {
+                    res.add_auth(words);
+                },
+                // Some older servers use the `AUTH=` form.
+                _ if keyword.starts_with("AUTH=") => {
+                    res.add_auth(Some(&keyword[5..]).into_iter().chain(words));
+                },
+                _ => {
+                    res.other.push((keyword, words.map(|s| s.to_string()).collect()));
+                },
+            }
+        }
+        res
+    }
+
+    /// The declared maximum message size, if the server has a fixed limit
+    pub fn max_size(&self) -> Option<u64> {
+        match self.size {
+            Some(0) | None => None,
+            size => size,
+        }
+    }
+
+    /// Tells if the server advertised the given SASL mechanism
+    pub fn supports_auth(&self, mechanism: &str) -> bool {
+        self.auth.iter().any(|name| name.eq_ignore_ascii_case(mechanism))
+    }
+
+    /// Tells if the server advertised the given keyword
+    ///
+    /// This checks both known extensions and unknown keywords.
+    pub fn supports(&self, keyword: &str) -> bool {
+        match keyword.to_ascii_uppercase().as_str() {
+            "SIZE" => self.size.is_some(),
+            "STARTTLS" => self.starttls,
+            "8BITMIME" => self.eight_bit_mime,
+            "BINARYMIME" => self.binary_mime,
+            "PIPELINING" => self.pipelining,
+            "SMTPUTF8" => self.smtp_utf8,
+            "CHUNKING" => self.chunking,
+            "DSN" => self.dsn,
+            "ENHANCEDSTATUSCODES" => self.enhanced_status_codes,
+            "AUTH" => !self.auth.is_empty(),
+            keyword => self.other.iter().any(|&(ref other, _)| other == keyword),
+        }
+    }
+
+    fn add_auth<'a, I: Iterator<Item = &'a str>>(&mut self, names: I) {
+        for name in names.filter(|name| !name.is_empty()) {
+            let name = name.to_ascii_uppercase();
+            if !self.auth.contains(&name) {
+                self.auth.push(name);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ehlo::{EhloCapabilities};
+
+    #[test]
+    fn test() {
+        let input = "250-mx.test Hello client.test\r\n\
+                     250-SIZE 35882577\r\n\
+                     250-8BITMIME\r\n\
+                     250-pipelining\r\n\
+                     250-AUTH LOGIN PLAIN\r\n\
+                     250-AUTH=LOGIN CRAM-MD5\r\n\
+                     250-ENHANCEDSTATUSCODES\r\n\
+                     250-X-EXPS GSSAPI NTLM\r\n\
+                     250 CHUNKING\r\n";
+        let caps = EhloCapabilities::from_response(&input.parse().unwrap());
+        assert_eq!(caps, EhloCapabilities {
+            domain: "mx.test".to_string(),
+            size: Some(35882577),
+            eight_bit_mime: true,
+            pipelining: true,
+            chunking: true,
+            enhanced_status_codes: true,
+            auth: vec!["LOGIN".to_string(), "PLAIN".to_string(), "CRAM-MD5".to_string()],
+            other: vec![
+                ("X-EXPS".to_string(), vec!["GSSAPI".to_string(), "NTLM".to_string()]),
+            ],
+            ..EhloCapabilities::default()
+        });
+        assert_eq!(caps.max_size(), Some(35882577));
+        assert!(caps.supports_auth("plain"));
+        assert!(!caps.supports_auth("XOAUTH2"));
+        assert!(caps.supports("x-exps"));
+        assert!(caps.supports("Pipelining"));
+        assert!(!caps.supports("STARTTLS"));
+
+        for (input, size, max_size) in vec![
+            ("250-mx.test\r\n250 SIZE\r\n", Some(0), None),
+            ("250-mx.test\r\n250 SIZE 0\r\n", Some(0), None),
+            ("250-mx.test\r\n250 SIZE 1000\r\n", Some(1000), Some(1000)),
+            ("250 mx.test\r\n", None, None),
+        ] {
+            let caps = EhloCapabilities::from_response(&input.parse().unwrap());
+            assert_eq!(caps.size, size);
+            assert_eq!(caps.max_size(), max_size);
+        }
+
+        let caps = EhloCapabilities::from_response(
+            &"250-mx.test\r\n250-STARTTLS\r\n250-DSN\r\n250-SMTPUTF8\r\n250 BINARYMIME\r\n"
+                .parse().unwrap());
+        assert!(caps.starttls && caps.dsn && caps.smtp_utf8 && caps.binary_mime);
+        assert!(!caps.eight_bit_mime && !caps.pipelining && caps.auth.is_empty());
+    }
+}
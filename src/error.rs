@@ -0,0 +1,268 @@
+//! The error type for client operations
+//!
+//! Where the tokio-proto machinery requires an `IoError`, an `SmtpError` is
+//! wrapped in one, and recovered again by `SmtpError::from`.
+
+use native_tls::{Error as TlsError};
+use request::{Mailbox, MailBodyParam, Request};
+use response::{Response, Severity};
+use std::error::{Error as StdError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use super::{RecipientStatus};
+
+
+/// An error that occurred while talking to a server
+#[derive(Debug)]
+pub enum SmtpError {
+    /// Connecting failed, or the connection failed
+    Io(IoError),
+    /// The TLS handshake failed
+    Tls(TlsError),
+    /// The server rejected the connection in its greeting, or rejected
+    /// `EHLO` and `HELO`
+    Handshake(Response),
+    /// TLS is required, but the server doesn't support `STARTTLS`
+    StartTlsUnsupported,
+    /// Refused to authenticate over an insecure connection
+    AuthInsecure,
+    /// The server supports none of the configured SASL mechanisms
+    AuthUnsupported,
+    /// The server rejected authentication, or failed to prove it knows the
+    /// credentials
+    AuthFailed(Response),
+    /// The server rejected a command with a transient (`4xx`) response
+    Transient { command: Request, response: Response },
+    /// The server rejected a command with a permanent (`5xx`) response
+    Permanent { command: Request, response: Response },
+    /// The server rejected all recipients
+    NoRecipients(Vec<RecipientStatus>),
+    /// The domain doesn't accept mail
+    NoMailExchanger(String),
+    /// Connecting, or waiting for the server, took too long
+    Timeout,
+    /// The server sent a malformed response
+    MalformedResponse,
+    /// Reading the message body failed
+    ///
+    /// The connection is closed without completing the message, so the
+    /// server discards it.
+    Body(IoError),
+    /// The server doesn't support the `BODY` type the message needs
+    ///
+    /// For a body of unknown type, this is only found out while it is sent,
+    /// and the connection is closed without completing the message.
+    BodyTypeUnsupported(MailBodyParam),
+    /// An address can only be sent with `SMTPUTF8`, which the server doesn't
+    /// support
+    SmtpUtf8Unsupported(Mailbox),
+    /// The message is larger than the server accepts, as declared with
+    /// `SIZE`
+    MessageTooLarge { size: usize, limit: u64 },
+}
+
+impl SmtpError {
+    /// Create an error for a rejected command, depending on the severity of
+    /// the response.
+    pub fn rejected(command: Request, response: Response) -> Self {
+        if response.code.severity == Severity::TransientNegativeCompletion {
+            SmtpError::Transient { command: command, response: response }
+        } else {
+            SmtpError::Permanent { command: command, response: response }
+        }
+    }
+
+    /// The response of the server that caused this error, if any
+    pub fn response(&self) -> Option<&Response> {
+        match *self {
+            SmtpError::Handshake(ref response) |
+            SmtpError::AuthFailed(ref response) |
+            SmtpError::Transient { ref response, .. } |
+            SmtpError::Permanent { ref response, .. } => Some(response),
+            _ => None,
+        }
+    }
+
+    /// Tells if trying again later may succeed
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            SmtpError::Io(_) | SmtpError::Timeout | SmtpError::Transient { .. } => true,
+            SmtpError::Handshake(ref response) | SmtpError::AuthFailed(ref response) => {
+                response.code.severity == Severity::TransientNegativeCompletion
+            },
+            SmtpError::NoRecipients(ref recipients) => {
+                recipients.iter().all(|status| {
+                    status.response.code.severity == Severity::TransientNegativeCompletion
+                })
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Display for SmtpError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            SmtpError::Io(ref err) => write!(f, "{}", err),
+            SmtpError::Tls(ref err) => write!(f, "tls error: {}", err),
+            SmtpError::Handshake(ref response) => {
+                write!(f, "handshake rejected: {}", ResponseLine(response))
+            },
+            SmtpError::AuthFailed(ref response) => {
+                write!(f, "authentication failed: {}", ResponseLine(response))
+            },
+            SmtpError::Transient { ref command, ref response } |
+            SmtpError::Permanent { ref command, ref response } => {
+                write!(f, "{} rejected: {}", command.to_string().trim(), ResponseLine(response))
+            },
+            SmtpError::NoMailExchanger(ref domain) => {
+                write!(f, "{}: {}", self.summary(), domain)
+            },
+            SmtpError::Body(ref err) => write!(f, "{}: {}", self.summary(), err),
+            SmtpError::BodyTypeUnsupported(ref body_type) => {
+                write!(f, "{}: {}", self.summary(), body_type)
+            },
+            SmtpError::SmtpUtf8Unsupported(ref mailbox) => {
+                write!(f, "{}: {}", self.summary(), mailbox)
+            },
+            SmtpError::MessageTooLarge { size, limit } => {
+                write!(f, "{}: {} octets, limit is {}", self.summary(), size, limit)
+            },
+            _ => write!(f, "{}", self.summary()),
+        }
+    }
+}
+
+impl StdError for SmtpError {
+    fn description(&self) -> &str {
+        self.summary()
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            SmtpError::Io(ref err) | SmtpError::Body(ref err) => Some(err),
+            SmtpError::Tls(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl SmtpError {
+    fn summary(&self) -> &'static str {
+        match *self {
+            SmtpError::Io(_) => "i/o error",
+            SmtpError::Tls(_) => "tls error",
+            SmtpError::Handshake(_) => "handshake rejected",
+            SmtpError::StartTlsUnsupported => "server doesn't support starttls",
+            SmtpError::AuthInsecure => "refusing to authenticate over an insecure connection",
+            SmtpError::AuthUnsupported => "server doesn't support any configured auth mechanism",
+            SmtpError::AuthFailed(_) => "authentication failed",
+            SmtpError::Transient { .. } => "command rejected with a transient error",
+            SmtpError::Permanent { .. } => "command rejected with a permanent error",
+            SmtpError::NoRecipients(_) => "no recipients accepted",
+            SmtpError::NoMailExchanger(_) => "domain doesn't accept mail",
+            SmtpError::Timeout => "timed out",
+            SmtpError::MalformedResponse => "malformed response",
+            SmtpError::Body(_) => "failed to read message body",
+            SmtpError::BodyTypeUnsupported(_) => "server doesn't support the message body type",
+            SmtpError::SmtpUtf8Unsupported(_) => "server doesn't support internationalized addresses",
+            SmtpError::MessageTooLarge { .. } => "message is too large for the server",
+        }
+    }
+}
+
+impl From<IoError> for SmtpError {
+    fn from(err: IoError) -> Self {
+        // Recover an `SmtpError` that was wrapped to pass through tokio-proto.
+        if err.get_ref().map_or(false, |inner| inner.is::<SmtpError>()) {
+            let inner = err.into_inner().expect("checked inner error");
+            return *inner.downcast::<SmtpError>().expect("checked inner error type");
+        }
+        SmtpError::Io(err)
+    }
+}
+
+impl From<TlsError> for SmtpError {
+    fn from(err: TlsError) -> Self {
+        SmtpError::Tls(err)
+    }
+}
+
+impl From<SmtpError> for IoError {
+    fn from(err: SmtpError) -> Self {
+        let kind = match err {
+            SmtpError::Io(err) => return err,
+            SmtpError::Timeout => IoErrorKind::TimedOut,
+            SmtpError::MalformedResponse => IoErrorKind::InvalidData,
+            _ => IoErrorKind::Other,
+        };
+        IoError::new(kind, err)
+    }
+}
+
+
+/// Formats a response on a single line, for use in messages
+struct ResponseLine<'a>(&'a Response);
+
+impl<'a> Display for ResponseLine<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.0.code)?;
+        for line in &self.0.text {
+            write!(f, " {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use error::{SmtpError};
+    use request::{MailBodyParam, Request};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+    #[test]
+    fn test() {
+        for (input, expect, transient) in vec![
+            ("421 Try later\r\n", "DATA rejected: 421 Try later", true),
+            ("554-No\r\n554 Go away\r\n", "DATA rejected: 554 No Go away", false),
+        ] {
+            let err = SmtpError::rejected(Request::Data, input.parse().unwrap());
+            assert_eq!(err.to_string(), expect);
+            assert_eq!(err.is_transient(), transient);
+            assert!(err.response().is_some());
+        }
+
+        // Round-trip through `IoError`.
+        let err: IoError = SmtpError::rejected(Request::Quit, "500 What\r\n".parse().unwrap()).into();
+        assert_eq!(err.kind(), IoErrorKind::Other);
+        match SmtpError::from(err) {
+            SmtpError::Permanent { command: Request::Quit, .. } => {},
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        let err: IoError = SmtpError::Timeout.into();
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+        assert!(SmtpError::from(err).is_transient());
+
+        let err = SmtpError::from(IoError::new(IoErrorKind::BrokenPipe, "broken pipe"));
+        assert_eq!(err.to_string(), "broken pipe");
+        let err: IoError = err.into();
+        assert_eq!(err.kind(), IoErrorKind::BrokenPipe);
+
+        let err = SmtpError::Body(IoError::new(IoErrorKind::Other, "disk on fire"));
+        assert_eq!(err.to_string(), "failed to read message body: disk on fire");
+        assert!(!err.is_transient());
+
+        let err = SmtpError::BodyTypeUnsupported(MailBodyParam::BinaryMime);
+        assert_eq!(err.to_string(), "server doesn't support the message body type: BINARYMIME");
+        assert!(!err.is_transient() && err.response().is_none());
+
+        let err = SmtpError::SmtpUtf8Unsupported("j\u{f8}rn@example.test".parse().unwrap());
+        assert_eq!(err.to_string(), "server doesn't support internationalized addresses: <j\u{f8}rn@example.test>");
+
+        let err = SmtpError::MessageTooLarge { size: 2048, limit: 1024 };
+        assert_eq!(err.to_string(), "message is too large for the server: 2048 octets, limit is 1024");
+        assert!(!err.is_transient());
+    }
+}
@@ -3,10 +3,14 @@
 //! The toplevel module exports a basic interface to send mail, through the
 //! `Mailer` type. This interface is hopefully sufficient for the common use
 //! case where mail just needs to be delivered to a trusted local mail server
-//! or remote mail service.
+//! or remote mail service. Mail can also be delivered directly to the mail
+//! exchangers of recipients, through the `MxMailer` in [the mx module](mx/).
+//! Messages that must survive a restart can be spooled to disk and delivered
+//! in the background, using [the queue module](queue/).
 //!
 //! A low-level client implementation on top of [tokio-proto] is available in
-//! [the client module](client/). The server-side is not yet implemented.
+//! [the client module](client/), and the server-side is available in
+//! [the server module](server/).
 //!
 //!  [Tokio]: https://tokio.rs/
 //!  [tokio-proto]: https://docs.rs/tokio-proto/
@@ -31,7 +35,8 @@
 //!     // Create a mailer that delivers to `localhost:25`.
 //!     let mailer = Mailer::local();
 //!
-//!     // Send an email. The `send` method returns an empty future (`()`).
+//!     // Send an email. The `send` method returns a future of a report with
+//!     // the status of every recipient.
 //!     let return_path = "john@example.test".parse().unwrap();
 //!     let recipient = "alice@example.test".parse().unwrap();
 //!     let body = TEST_EML.to_string();
@@ -42,14 +47,18 @@
 //! }
 //! ```
 
-// FIXME: Add server protocol
-
+extern crate base64;
 extern crate emailaddress;
+#[macro_use]
 extern crate futures;
+extern crate hmac;
+extern crate md5;
 extern crate native_tls;
 #[macro_use]
 extern crate nom;
 extern crate bytes;
+extern crate rand;
+extern crate sha2;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
@@ -58,35 +67,80 @@ extern crate tokio_tls;
 #[macro_use]
 extern crate log;
 
+pub mod auth;
 pub mod client;
+pub mod ehlo;
+pub mod error;
+pub mod mx;
+pub mod queue;
 pub mod request;
 pub mod response;
+pub mod server;
+mod pool;
+mod retry;
 mod util;
 
-use client::{ClientParams, ClientProto, ClientSecurity, ClientTlsParams};
-use futures::{future, Future, Sink};
+use auth::{ClientAuth, Credentials, SaslMechanism};
+use bytes::{BytesMut};
+use client::{ClientParams, ClientProto, ClientSecurity, ClientService, ClientTimeouts, ClientTlsParams, ConnectionInfo};
+use ehlo::{EhloCapabilities};
+use error::{SmtpError};
+use futures::{future, stream, Future, Sink, Stream};
+use futures::future::{Loop};
 use native_tls::{TlsConnector};
-use request::{ClientId, Mailbox, Request as SmtpRequest};
+use pool::{Pool};
+use request::{ClientId, DsnNotify, DsnReturn, Mailbox, MailBodyParam, MailParam, RcptParam, Request as SmtpRequest};
+use response::{Response, Severity};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::{Arc};
+use std::time::{Duration};
 use tokio_core::reactor::{Handle};
-use tokio_proto::{TcpClient as TokioTcpClient};
 use tokio_proto::streaming::{Body, Message};
 use tokio_service::{Service};
+use util::{BodyEncoder, abortable_body, check_seven_bit, detect_body_type, interleave_families,
+           is_eight_bit_error, normalized_len};
 
 pub type MailBody = Body<Vec<u8>, IoError>;
 
+type BodyStream = Box<Stream<Item = Vec<u8>, Error = IoError>>;
+
+
+/// What is known about a message before it is sent
+#[derive(Clone,Debug,Default)]
+struct MessageInfo {
+    body_type: Option<MailBodyParam>,
+    size: Option<usize>,
+    normalized_size: Option<usize>,
+    dsn: Option<DsnParams>,
+}
+
+impl MessageInfo {
+    fn of<B: IntoMailBody>(body: &B, dsn: Option<DsnParams>) -> MessageInfo {
+        MessageInfo {
+            body_type: body.body_type(),
+            size: body.body_size(),
+            normalized_size: body.normalized_body_size(),
+            dsn: dsn,
+        }
+    }
+}
+
 
 struct MailerParams {
     addrs: Vec<SocketAddr>,
     params: Arc<ClientParams>,
+    pool: Option<Pool>,
+    retry: Option<RetryPolicy>,
 }
 
 
 /// Object used to send mail to a specific server.
 ///
-/// A `Mailer` is created using a `MailerBuilder`.
+/// A `Mailer` is created using a `MailerBuilder`. Unless a pool is configured,
+/// every call to `send` uses a new connection; use `session` to send several
+/// messages over one.
+#[derive(Clone)]
 pub struct Mailer(Arc<MailerParams>);
 
 impl Mailer {
@@ -102,50 +156,667 @@ impl Mailer {
     }
 
     /// Send an email.
+    ///
+    /// The message is delivered to all recipients the server accepts. This
+    /// fails only if no recipient was accepted, or if the message itself was
+    /// rejected.
+    ///
+    /// With a retry policy, failures are retried for the recipients that
+    /// were not accepted, and the report has the final status of every
+    /// recipient.
+    ///
+    /// The `BODY` type and size of the message are declared when the server
+    /// supports it, and the send fails up front if the server can't accept
+    /// the message. See `IntoMailBody::body_type` and `body_size`.
     pub fn send<B: IntoMailBody>(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: B, handle: &Handle)
-            -> Box<Future<Item = (), Error = IoError>> {
-        self.send_raw(return_path, recipients, body.into_mail_body(handle), handle)
-    }
-
-    fn send_raw(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: MailBody, handle: &Handle)
-            -> Box<Future<Item = (), Error = IoError>> {
-        // FIXME: Iterate addrs.
-        Box::new(TokioTcpClient::new(ClientProto(self.0.params.clone()))
-            .connect(&self.0.addrs[0], handle)
-            .and_then(move |service| {
-                let mut reqs = Vec::with_capacity(4);
-                reqs.push(service.call(
-                    Message::WithoutBody(SmtpRequest::Mail {
-                        from: return_path,
-                        params: vec![],
-                    })
-                ));
-                for recipient in recipients {
-                    reqs.push(service.call(
-                        Message::WithoutBody(SmtpRequest::Rcpt {
-                            to: recipient,
-                            params: vec![],
+            -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+        let message = MessageInfo::of(&body, None);
+        self.send_message(return_path, recipients, body.into_mail_body(handle), message, handle)
+    }
+
+    /// Send an email, and request delivery status notifications.
+    ///
+    /// The notification parameters are only sent if the server supports
+    /// `DSN`. Otherwise, this is the same as `send`.
+    pub fn send_with_dsn<B: IntoMailBody>(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: B,
+                                          dsn: DsnParams, handle: &Handle)
+            -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+        let message = MessageInfo::of(&body, Some(dsn));
+        self.send_message(return_path, recipients, body.into_mail_body(handle), message, handle)
+    }
+
+    fn send_message(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: MailBody, message: MessageInfo,
+                    handle: &Handle)
+            -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+        match self.0.retry {
+            Some(ref policy) => retry::send(self.clone(), policy.clone(), return_path, recipients, body, message, handle),
+            None => self.send_raw(return_path, recipients, body, message, handle),
+        }
+    }
+
+    fn send_raw(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: MailBody, message: MessageInfo,
+                handle: &Handle)
+            -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+        if let Some(ref pool) = self.0.pool {
+            let mailer = self.clone();
+            let connect_handle = handle.clone();
+            let handle = handle.clone();
+            return Box::new(pool.checkout(move || mailer.session(&connect_handle), &handle)
+                .and_then(move |(session, slot)| {
+                    // Dropping the slot stops counting a connection that failed.
+                    session.send_message(return_path, recipients, body, message, &handle)
+                        .and_then(move |(session, result)| {
+                            slot.checkin(session, &handle);
+                            result
                         })
-                    ));
+                }));
+        }
+
+        let handle = handle.clone();
+        Box::new(self.session(&handle)
+            .and_then(move |session| {
+                session.send_and_close(return_path, recipients, body, message, &handle)
+            }))
+    }
+
+    /// Open a session, to send several messages over one connection.
+    ///
+    /// This always opens a new connection, even if a pool is configured.
+    pub fn session(&self, handle: &Handle) -> Box<Future<Item = Session, Error = SmtpError>> {
+        connect_any(self.0.addrs.clone(), self.0.params.clone(), handle)
+    }
+}
+
+/// Connect to the first address that accepts us.
+///
+/// Moves on to the next address if connecting fails, or the server is
+/// unavailable.
+fn connect_any(addrs: Vec<SocketAddr>, params: Arc<ClientParams>, handle: &Handle)
+        -> Box<Future<Item = Session, Error = SmtpError>> {
+    let handle = handle.clone();
+    Box::new(future::loop_fn(0, move |idx| {
+        let addr = addrs[idx];
+        let is_last = idx + 1 == addrs.len();
+        let normalize_line_endings = params.normalize_line_endings;
+        ClientProto(params.clone()).connect(&addr, &handle)
+            .then(move |result| {
+                match result {
+                    Ok((info, service)) => Ok(Loop::Break(Session {
+                        addr: addr,
+                        info: info,
+                        service: service,
+                        normalize_line_endings: normalize_line_endings,
+                        transactions: 0,
+                    })),
+                    Err(ref err) if !is_last && is_unavailable(err) => {
+                        debug!("failed to connect to {}: {}", addr, err);
+                        Ok(Loop::Continue(idx + 1))
+                    },
+                    Err(err) => Err(err),
                 }
-                reqs.push(service.call(
-                    Message::WithBody(SmtpRequest::Data, body)
-                ));
-                reqs.push(service.call(
-                    Message::WithoutBody(SmtpRequest::Quit)
-                ));
-                future::join_all(reqs)
             })
-            .and_then(|responses| {
-                for response in responses {
-                    let response = response.into_inner();
-                    if !response.code.severity.is_positive() {
-                        return future::err(IoError::new(IoErrorKind::Other,
-                            format!("bad smtp response {}", response.code)))
-                    }
+    }))
+}
+
+/// Tells if the server is unavailable, and another should be tried.
+fn is_unavailable(err: &SmtpError) -> bool {
+    match *err {
+        SmtpError::Io(_) | SmtpError::Timeout => true,
+        SmtpError::Handshake(ref response) => {
+            match response.code.numeric() {
+                421 | 554 => true,
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Tells if the connection can no longer be used after this error.
+fn is_closed(err: &SmtpError) -> bool {
+    match *err {
+        SmtpError::Io(_) | SmtpError::Timeout | SmtpError::MalformedResponse |
+        SmtpError::Body(_) => true,
+        // The server is closing the connection.
+        _ => err.response().map_or(false, |response| response.code.numeric() == 421),
+    }
+}
+
+/// Send a request, and wait for the response.
+///
+/// The service applies the timeouts once the request is sent.
+fn call(service: &ClientService, request: Message<SmtpRequest, MailBody>)
+        -> Box<Future<Item = Response, Error = SmtpError>> {
+    Box::new(service.call(request)
+        .map(|response| response.into_inner())
+        .map_err(SmtpError::from))
+}
+
+
+/// A connection to a server, over which several messages can be sent.
+///
+/// A `Session` is created using `Mailer::session`. The connection stays open
+/// until `close` is called, or the server closes it.
+pub struct Session {
+    addr: SocketAddr,
+    info: ConnectionInfo,
+    service: ClientService,
+    normalize_line_endings: bool,
+    transactions: usize,
+}
+
+impl Session {
+    /// The address of the server
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Information about the connection
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// The number of messages sent over this session, including rejected
+    /// messages
+    pub fn transactions(&self) -> usize {
+        self.transactions
+    }
+
+    /// Send an email over this session.
+    ///
+    /// The future resolves to the session, to send the next message with,
+    /// and the result of this delivery. If the message is rejected, the
+    /// transaction is reset and the session can still be used. The future
+    /// fails only if the connection was lost or closed by the server, the
+    /// server did not respond in time, or the reset failed. In the last case,
+    /// the future fails with the error of the delivery.
+    pub fn send<B: IntoMailBody>(self, return_path: Mailbox, recipients: Vec<Mailbox>, body: B, handle: &Handle)
+            -> Box<Future<Item = (Session, Result<DeliveryReport, SmtpError>), Error = SmtpError>> {
+        let message = MessageInfo::of(&body, None);
+        self.send_message(return_path, recipients, body.into_mail_body(handle), message, handle)
+    }
+
+    /// Send an email over this session, and request delivery status
+    /// notifications.
+    ///
+    /// The notification parameters are only sent if the server supports
+    /// `DSN`. Otherwise, this is the same as `send`.
+    pub fn send_with_dsn<B: IntoMailBody>(self, return_path: Mailbox, recipients: Vec<Mailbox>, body: B,
+                                          dsn: DsnParams, handle: &Handle)
+            -> Box<Future<Item = (Session, Result<DeliveryReport, SmtpError>), Error = SmtpError>> {
+        let message = MessageInfo::of(&body, Some(dsn));
+        self.send_message(return_path, recipients, body.into_mail_body(handle), message, handle)
+    }
+
+    fn send_message(mut self, return_path: Mailbox, recipients: Vec<Mailbox>, body: MailBody, message: MessageInfo,
+                    handle: &Handle)
+            -> Box<Future<Item = (Session, Result<DeliveryReport, SmtpError>), Error = SmtpError>> {
+        let handle = handle.clone();
+        self.transactions += 1;
+        Box::new(self.transaction(return_path, recipients, body, message, &handle)
+            .then(move |result| {
+                match result {
+                    Ok(report) => future::Either::A(future::ok((self, Ok(report)))),
+                    Err(err) => {
+                        if is_closed(&err) {
+                            future::Either::A(future::err(err))
+                        } else {
+                            // If the reset fails, the session is unusable,
+                            // but the delivery error is the one to report.
+                            future::Either::B(self.reset()
+                                .then(move |reset| match reset {
+                                    Ok(session) => Ok((session, Err(err))),
+                                    Err(_) => Err(err),
+                                }))
+                        }
+                    },
+                }
+            }))
+    }
+
+    /// Abort the current transaction, using `RSET`.
+    pub fn reset(self) -> Box<Future<Item = Session, Error = SmtpError>> {
+        self.command(SmtpRequest::Rset)
+    }
+
+    /// Check that the server is still responding, using `NOOP`.
+    pub fn noop(self) -> Box<Future<Item = Session, Error = SmtpError>> {
+        self.command(SmtpRequest::Noop)
+    }
+
+    /// Close the session, using `QUIT`.
+    pub fn close(self) -> Box<Future<Item = (), Error = SmtpError>> {
+        Box::new(call(&self.service, Message::WithoutBody(SmtpRequest::Quit))
+            .map(|_| ()))
+    }
+
+    fn command(self, request: SmtpRequest) -> Box<Future<Item = Session, Error = SmtpError>> {
+        Box::new(call(&self.service, Message::WithoutBody(request.clone()))
+            .and_then(move |response| {
+                if response.code.severity.is_positive() {
+                    Ok(self)
+                } else {
+                    Err(SmtpError::rejected(request, response))
+                }
+            }))
+    }
+
+    /// Send an email, then close the session.
+    fn send_and_close(self, return_path: Mailbox, recipients: Vec<Mailbox>, body: MailBody, message: MessageInfo,
+                      handle: &Handle)
+            -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+        let handle = handle.clone();
+        Box::new(self.transaction(return_path, recipients, body, message, &handle)
+            .then(move |result| {
+                // The outcome of the transaction is known at this point,
+                // regardless of the response to `QUIT`.
+                self.close().then(move |_| result)
+            }))
+    }
+
+    /// Run a single mail transaction.
+    fn transaction(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: MailBody, message: MessageInfo,
+                   handle: &Handle)
+            -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+        let capabilities = &self.info.capabilities;
+        let unknown = message.body_type.is_none();
+        let body_param = match body_param(message.body_type, capabilities) {
+            Ok(body_param) => body_param,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        // Binary bodies are sent exactly as they are.
+        let binary = body_param == Some(MailBodyParam::BinaryMime);
+        let normalize = self.normalize_line_endings && !binary;
+
+        // The size is declared as sent, with line endings converted.
+        let size = if normalize { message.normalized_size } else { message.size };
+        let size_param = match size_param(size, capabilities.size) {
+            Ok(size_param) => size_param,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        // Without `8BITMIME`, a body of unknown type must be plain ASCII. If
+        // it isn't, the message is aborted while it is sent.
+        let body: BodyStream = if unknown && !capabilities.eight_bit_mime {
+            Box::new(body.and_then(check_seven_bit))
+        } else {
+            Box::new(body)
+        };
+
+        // Internationalized addresses need `SMTPUTF8`. Without it, domains
+        // are converted to ASCII, which fails for non-ASCII local parts.
+        let mut params = body_param.into_iter().map(MailParam::Body).chain(size_param).collect::<Vec<_>>();
+        let (return_path, rcpt_to) = if return_path.is_ascii() && recipients.iter().all(Mailbox::is_ascii) {
+            (return_path, recipients.clone())
+        } else if capabilities.smtp_utf8 {
+            params.push(MailParam::SmtpUtf8);
+            (return_path, recipients.clone())
+        } else {
+            match ascii_envelope(&return_path, &recipients) {
+                Ok(addresses) => addresses,
+                Err(err) => return Box::new(future::err(err)),
+            }
+        };
+
+        // Delivery status notifications (RFC 3461) are only requested from
+        // servers that support `DSN`.
+        let dsn = message.dsn.as_ref().filter(|_| capabilities.dsn);
+        if let Some(dsn) = dsn {
+            params.extend(dsn.ret.map(MailParam::Ret));
+            params.extend(dsn.envelope_id.clone().map(MailParam::EnvId));
+        }
+        let rcpt_reqs = rcpt_to.into_iter().zip(&recipients)
+            .map(|(to, original)| SmtpRequest::Rcpt {
+                to: to,
+                params: dsn.map_or(vec![], |dsn| dsn.rcpt_params(original)),
+            })
+            .collect::<Vec<_>>();
+
+        // Send the envelope. With `PIPELINING` (RFC 2920), `MAIL` and all
+        // `RCPT` commands are sent at once, and a rejected `MAIL` is reported
+        // even if the connection is lost while waiting for the other
+        // responses. Otherwise, every command waits for the previous response.
+        let mail = SmtpRequest::Mail {
+            from: return_path,
+            params: params,
+        };
+        let mail_req = call(&self.service, Message::WithoutBody(mail.clone()))
+            .and_then(move |response| {
+                if response.code.severity.is_positive() {
+                    Ok(())
+                } else {
+                    Err(SmtpError::rejected(mail, response))
                 }
-                future::ok(())
+            });
+        let envelope: Box<Future<Item = Vec<Response>, Error = SmtpError>> = if self.info.capabilities.pipelining {
+            let rcpt_reqs = rcpt_reqs.into_iter()
+                .map(|request| call(&self.service, Message::WithoutBody(request)))
+                .collect::<Vec<_>>();
+            Box::new(mail_req
+                .join(future::join_all(rcpt_reqs))
+                .map(|((), responses)| responses))
+        } else {
+            let service = self.service.clone();
+            Box::new(mail_req.and_then(move |()| {
+                stream::iter_ok(rcpt_reqs)
+                    .and_then(move |request| call(&service, Message::WithoutBody(request)))
+                    .collect()
             }))
+        };
+
+        let service = self.service.clone();
+        let addr = self.addr;
+        let info = self.info.clone();
+        let chunking = info.capabilities.chunking;
+        let pipelining = info.capabilities.pipelining;
+        let handle = handle.clone();
+        Box::new(envelope
+            .and_then(move |responses| {
+                let recipients = recipients.into_iter()
+                    .zip(responses)
+                    .map(|(recipient, response)| RecipientStatus {
+                        recipient: recipient,
+                        response: response,
+                    })
+                    .collect::<Vec<_>>();
+
+                // Only send the message if there is anyone to deliver to.
+                if !recipients.iter().any(RecipientStatus::is_accepted) {
+                    return Err(SmtpError::NoRecipients(recipients));
+                }
+                Ok(recipients)
+            })
+            .and_then(move |recipients| {
+                let content = if chunking {
+                    send_chunks(service, body, normalize, pipelining)
+                } else {
+                    send_data(&service, body, &handle)
+                };
+                content.map_err(|err| match err {
+                    SmtpError::Body(ref err) if is_eight_bit_error(err) => {
+                        SmtpError::BodyTypeUnsupported(MailBodyParam::EightBitMime)
+                    },
+                    err => err,
+                })
+                .and_then(move |(request, response)| {
+                    if response.code.severity.is_positive() {
+                        Ok(DeliveryReport {
+                            addr: addr,
+                            connection: info,
+                            recipients: recipients,
+                            data_response: response,
+                        })
+                    } else {
+                        Err(SmtpError::rejected(request, response))
+                    }
+                })
+            }))
+    }
+}
+
+/// Convert the addresses of an envelope to ASCII, for a server without
+/// `SMTPUTF8`.
+fn ascii_envelope(return_path: &Mailbox, recipients: &[Mailbox]) -> Result<(Mailbox, Vec<Mailbox>), SmtpError> {
+    let to_ascii = |mailbox: &Mailbox| {
+        mailbox.to_ascii().ok_or_else(|| SmtpError::SmtpUtf8Unsupported(mailbox.clone()))
+    };
+    Ok((to_ascii(return_path)?, recipients.iter().map(to_ascii).collect::<Result<_, _>>()?))
+}
+
+/// Choose the `BODY` parameter for a message of the given type.
+///
+/// A message of unknown type is declared `8BITMIME` if the server supports
+/// it. Fails if the server doesn't support the type of the message.
+fn body_param(body_type: Option<MailBodyParam>, capabilities: &EhloCapabilities)
+        -> Result<Option<MailBodyParam>, SmtpError> {
+    match body_type {
+        // The `BODY` parameter itself is part of `8BITMIME`.
+        None | Some(MailBodyParam::SevenBit) if !capabilities.eight_bit_mime => Ok(None),
+        None => Ok(Some(MailBodyParam::EightBitMime)),
+        Some(MailBodyParam::EightBitMime) if !capabilities.eight_bit_mime => {
+            Err(SmtpError::BodyTypeUnsupported(MailBodyParam::EightBitMime))
+        },
+        // Binary bodies can only be sent with `BDAT`.
+        Some(MailBodyParam::BinaryMime) if !(capabilities.binary_mime && capabilities.chunking) => {
+            Err(SmtpError::BodyTypeUnsupported(MailBodyParam::BinaryMime))
+        },
+        body_type => Ok(body_type),
+    }
+}
+
+/// Choose the `SIZE` parameter (RFC 1870) for a message of the given size.
+///
+/// A message of known size is declared, and refused right away if it
+/// exceeds the limit of the server.
+fn size_param(size: Option<usize>, limit: Option<u64>) -> Result<Option<MailParam>, SmtpError> {
+    match (size, limit) {
+        (Some(size), Some(limit)) if limit > 0 && size as u64 > limit => {
+            Err(SmtpError::MessageTooLarge { size: size, limit: limit })
+        },
+        (Some(size), Some(_)) => Ok(Some(MailParam::Size(size))),
+        _ => Ok(None),
+    }
+}
+
+/// Send the message with `DATA`.
+fn send_data(service: &ClientService, body: BodyStream, handle: &Handle)
+        -> Box<Future<Item = (SmtpRequest, Response), Error = SmtpError>> {
+    // If the body fails, the connection is closed, and the error of the body
+    // is reported instead.
+    let aborter = service.clone();
+    let (body, body_error) = abortable_body(body, move || aborter.abort_body(), handle);
+    Box::new(call(service, Message::WithBody(SmtpRequest::Data, body))
+        .map_err(move |err| body_error.borrow_mut().take().map_or(err, SmtpError::Body))
+        .map(|response| (SmtpRequest::Data, response)))
+}
+
+/// How many `BDAT` chunks are sent ahead of their responses, at most
+const MAX_PIPELINED_CHUNKS: usize = 8;
+
+/// Send the message in `BDAT` chunks (RFC 3030).
+///
+/// Every chunk of the body is sent as it arrives, and the end of the body is
+/// marked with a last chunk, which is empty unless a held back line ending
+/// remains. With `PIPELINING`, up to `MAX_PIPELINED_CHUNKS` chunks are sent
+/// without waiting for responses. The response to the last chunk is
+/// returned, unless an earlier chunk was rejected.
+fn send_chunks(service: ClientService, body: BodyStream, normalize: bool, pipelining: bool)
+        -> Box<Future<Item = (SmtpRequest, Response), Error = SmtpError>> {
+    let encoder = BodyEncoder::new(normalize, false);
+    let limit = if pipelining { MAX_PIPELINED_CHUNKS } else { 1 };
+    Box::new(future::loop_fn((body, encoder, vec![]), move |(body, mut encoder, mut pending)| {
+        let service = service.clone();
+        body.into_future()
+            .map_err(|(err, _)| SmtpError::Body(err))
+            .and_then(move |(chunk, body)| {
+                let mut buf = BytesMut::new();
+                let last = match chunk {
+                    Some(chunk) => {
+                        encoder.encode(&chunk, &mut buf);
+                        false
+                    },
+                    None => {
+                        encoder.flush(&mut buf);
+                        true
+                    },
+                };
+                let step: Box<Future<Item = Loop<_, _>, Error = SmtpError>> = if buf.is_empty() && !last {
+                    Box::new(future::ok(Loop::Continue((body, encoder, pending))))
+                } else {
+                    let request = SmtpRequest::Bdat { size: buf.len(), last: last };
+                    let message = if buf.is_empty() {
+                        Message::WithBody(request.clone(), Body::empty())
+                    } else {
+                        Message::WithBody(request.clone(), Body::from(buf.to_vec()))
+                    };
+                    let response: Box<Future<Item = (SmtpRequest, Response), Error = SmtpError>> =
+                        Box::new(call(&service, message)
+                            .map(move |response| (request, response)));
+                    pending.push(response);
+                    if last {
+                        Box::new(future::ok(Loop::Break(pending)))
+                    } else if pending.len() < limit {
+                        Box::new(future::ok(Loop::Continue((body, encoder, pending))))
+                    } else {
+                        // Wait for the oldest chunk, so the body isn't
+                        // buffered without bound.
+                        Box::new(pending.remove(0).and_then(move |(request, response)| {
+                            if response.code.severity.is_positive() {
+                                Ok(Loop::Continue((body, encoder, pending)))
+                            } else {
+                                Err(SmtpError::rejected(request, response))
+                            }
+                        }))
+                    }
+                };
+                step
+            })
+    })
+    .and_then(future::join_all)
+    .and_then(|mut responses| {
+        let last = responses.pop().expect("response to last chunk");
+        match responses.into_iter().find(|&(_, ref response)| !response.code.severity.is_positive()) {
+            Some((request, response)) => Err(SmtpError::rejected(request, response)),
+            None => Ok(last),
+        }
+    }))
+}
+
+
+/// The status of a single recipient of a delivery
+#[derive(Clone,Debug)]
+pub struct RecipientStatus {
+    /// The recipient address
+    pub recipient: Mailbox,
+    /// The response to `RCPT TO`
+    pub response: Response,
+}
+
+impl RecipientStatus {
+    /// Tells if the server accepted the recipient
+    pub fn is_accepted(&self) -> bool {
+        self.response.code.severity.is_positive()
+    }
+}
+
+
+/// The result of a delivery by `Mailer::send`
+#[derive(Clone,Debug)]
+pub struct DeliveryReport {
+    /// The address of the server that accepted the message
+    pub addr: SocketAddr,
+    /// Information about the connection used
+    pub connection: ConnectionInfo,
+    /// The status of every recipient, in order
+    pub recipients: Vec<RecipientStatus>,
+    /// The response to the message
+    pub data_response: Response,
+}
+
+impl DeliveryReport {
+    /// The recipients the message was delivered to
+    pub fn accepted(&self) -> Vec<&RecipientStatus> {
+        self.recipients.iter().filter(|status| status.is_accepted()).collect()
+    }
+
+    /// The recipients the server rejected
+    pub fn rejected(&self) -> Vec<&RecipientStatus> {
+        self.recipients.iter().filter(|status| !status.is_accepted()).collect()
+    }
+}
+
+
+/// Settings for the connection pool of a `Mailer`
+#[derive(Clone,Debug)]
+pub struct PoolConfig {
+    /// The maximum number of connections, idle or in use
+    pub max_size: usize,
+    /// How long a connection may stay idle before it is closed
+    pub idle_timeout: Duration,
+    /// The number of messages after which a connection is closed
+    pub max_messages: Option<usize>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 4,
+            idle_timeout: Duration::from_secs(60),
+            max_messages: Some(100),
+        }
+    }
+}
+
+
+/// Settings for retrying failed sends of a `Mailer`
+///
+/// The delay before retry `n` is `initial_delay * multiplier^(n - 1)`, capped
+/// at `max_delay`, and randomly varied by up to `jitter` (a fraction) in
+/// either direction.
+#[derive(Clone,Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first
+    pub max_attempts: u32,
+    /// The delay before the first retry
+    pub initial_delay: Duration,
+    /// The factor the delay grows by with every retry
+    pub multiplier: f64,
+    /// The maximum delay between attempts
+    pub max_delay: Duration,
+    /// The fraction of the delay to randomly add or subtract, from 0 to 1
+    pub jitter: f64,
+    /// The severities of responses that are retried
+    ///
+    /// Connection failures and timeouts are retried if
+    /// `TransientNegativeCompletion` is included.
+    pub severities: Vec<Severity>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.1,
+            severities: vec![Severity::TransientNegativeCompletion],
+        }
+    }
+}
+
+
+/// Delivery status notification settings for a message (RFC 3461)
+#[derive(Clone,Debug,Default)]
+pub struct DsnParams {
+    /// Whether failure notifications include the full message, or only its
+    /// headers
+    pub ret: Option<DsnReturn>,
+    /// An identifier for the message, included in notifications
+    pub envelope_id: Option<String>,
+    /// When to notify the sender about each recipient
+    pub notify: Option<DsnNotify>,
+    /// Send the address of every recipient with `ORCPT`, so notifications
+    /// refer to it even if the message is forwarded
+    pub original_recipients: bool,
+}
+
+impl DsnParams {
+    /// The `RCPT TO` parameters for a recipient
+    fn rcpt_params(&self, recipient: &Mailbox) -> Vec<RcptParam> {
+        let mut params = self.notify.map(RcptParam::Notify).into_iter().collect::<Vec<_>>();
+        if let Some(ref addr) = recipient.0 {
+            if self.original_recipients {
+                // Internationalized addresses have their own type (RFC 6533).
+                let addr_type = if recipient.is_ascii() { "rfc822" } else { "utf-8" };
+                params.push(RcptParam::OriginalRecipient {
+                    addr_type: addr_type.to_string(),
+                    addr: addr.to_string(),
+                });
+            }
+        }
+        params
     }
 }
 
@@ -155,6 +826,14 @@ pub struct MailerBuilder {
     server: String,
     client_id: ClientId,
     tls_connector: Option<TlsConnector>,
+    credentials: Option<Credentials>,
+    sasl_mechanisms: Vec<Arc<SaslMechanism + Send + Sync>>,
+    allow_insecure_auth: bool,
+    require_esmtp: bool,
+    timeouts: ClientTimeouts,
+    normalize_line_endings: bool,
+    pool: Option<PoolConfig>,
+    retry: Option<RetryPolicy>,
 }
 
 impl MailerBuilder {
@@ -164,6 +843,14 @@ impl MailerBuilder {
             server: server,
             client_id: ClientId::Domain("localhost".to_string()),
             tls_connector: None,
+            credentials: None,
+            sasl_mechanisms: vec![],
+            allow_insecure_auth: false,
+            require_esmtp: false,
+            timeouts: ClientTimeouts::default(),
+            normalize_line_endings: true,
+            pool: None,
+            retry: None,
         }
     }
 
@@ -188,9 +875,104 @@ impl MailerBuilder {
         self
     }
 
+    /// Authenticate with the given username and password.
+    ///
+    /// By default, no authentication is done.
+    pub fn set_credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some(Credentials::new(username, password));
+        self
+    }
+
+    /// Authenticate using a custom SASL mechanism.
+    ///
+    /// Custom mechanisms are preferred over the built-in ones enabled by
+    /// `set_credentials`, in the order they were added.
+    pub fn add_sasl_mechanism<M>(mut self, mechanism: M) -> Self
+            where M: SaslMechanism + Send + Sync + 'static {
+        self.sasl_mechanisms.push(Arc::new(mechanism));
+        self
+    }
+
+    /// Allow sending credentials over a connection without TLS.
+    ///
+    /// By default, authentication fails if the connection is not secure.
+    pub fn set_allow_insecure_auth(mut self, allow: bool) -> Self {
+        self.allow_insecure_auth = allow;
+        self
+    }
+
+    /// Require the server to support ESMTP.
+    ///
+    /// By default, `HELO` is sent if the server rejects `EHLO`, and no
+    /// extensions are used.
+    pub fn set_require_esmtp(mut self, require: bool) -> Self {
+        self.require_esmtp = require;
+        self
+    }
+
+    /// Set how long to wait for the server.
+    ///
+    /// By default, the timeouts from RFC 5321 are used.
+    pub fn set_timeouts(mut self, timeouts: ClientTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Convert bare `CR` and `LF` in message bodies to `CRLF`.
+    ///
+    /// By default, line endings are converted.
+    pub fn set_normalize_line_endings(mut self, normalize: bool) -> Self {
+        self.normalize_line_endings = normalize;
+        self
+    }
+
+    /// Keep connections open in a pool, and reuse them for sends.
+    ///
+    /// Pooled connections are checked with `NOOP` before reuse. By default,
+    /// no pool is used, and every send opens a new connection.
+    ///
+    /// The maximum size must be at least 1, or `build` fails.
+    pub fn set_pool(mut self, config: PoolConfig) -> Self {
+        self.pool = Some(config);
+        self
+    }
+
+    /// Retry sends that fail, according to the policy.
+    ///
+    /// Only recipients that failed with one of the retried severities are
+    /// retried. The message body is buffered in memory, so it can be sent
+    /// again. By default, failures are not retried.
+    ///
+    /// Building fails if the maximum number of attempts is 0.
+    pub fn set_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
     /// Transform this builder into a `Mailer`.
     pub fn build(self) -> IoResult<Mailer> {
-        let addrs = self.server.to_socket_addrs()?.collect();
+        let addrs = interleave_families(self.server.to_socket_addrs()?.collect());
+        if addrs.is_empty() {
+            return Err(IoError::new(IoErrorKind::AddrNotAvailable, "no addresses for server"));
+        }
+        if self.pool.as_ref().map_or(false, |config| config.max_size == 0) {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "pool must allow at least one connection"));
+        }
+        if self.retry.as_ref().map_or(false, |policy| policy.max_attempts == 0) {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "retry policy must allow at least one attempt"));
+        }
+        let mut mechanisms = self.sasl_mechanisms;
+        if let Some(credentials) = self.credentials {
+            mechanisms.extend(ClientAuth::new(credentials).mechanisms);
+        }
+        let auth = if mechanisms.is_empty() {
+            None
+        } else {
+            Some(ClientAuth {
+                mechanisms: mechanisms,
+                allow_insecure: self.allow_insecure_auth,
+            })
+        };
         Ok(Mailer(Arc::new(MailerParams {
             addrs: addrs,
             params: Arc::new(ClientParams {
@@ -203,7 +985,13 @@ impl MailerBuilder {
                             .nth(1).unwrap().to_string(),
                     }),
                 },
+                auth: auth,
+                require_esmtp: self.require_esmtp,
+                timeouts: self.timeouts,
+                normalize_line_endings: self.normalize_line_endings,
             }),
+            pool: self.pool.map(Pool::new),
+            retry: self.retry,
         })))
     }
 }
@@ -218,6 +1006,32 @@ pub trait IntoMailBody {
     ///
     /// The handle can optionally be used to write the body.
     fn into_mail_body(self, &Handle) -> MailBody;
+
+    /// The `BODY` type of the message, if known before it is sent.
+    ///
+    /// Bodies of unknown type are declared `8BITMIME` if the server supports
+    /// it, and must be plain ASCII otherwise.
+    fn body_type(&self) -> Option<MailBodyParam> {
+        None
+    }
+
+    /// The size of the message in octets, if known before it is sent.
+    ///
+    /// The size is declared to servers that support `SIZE`, and the send
+    /// fails up front if it exceeds their limit. When line endings are
+    /// converted, `normalized_body_size` is used instead.
+    fn body_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// The size of the message in octets once bare `CR` and `LF` are
+    /// converted to `CRLF`, if known before it is sent.
+    ///
+    /// By default, this is unknown, and no size is declared when line
+    /// endings are converted.
+    fn normalized_body_size(&self) -> Option<usize> {
+        None
+    }
 }
 
 impl IntoMailBody for MailBody {
@@ -227,6 +1041,18 @@ impl IntoMailBody for MailBody {
 }
 
 impl IntoMailBody for Vec<u8> {
+    fn body_type(&self) -> Option<MailBodyParam> {
+        Some(detect_body_type(self))
+    }
+
+    fn body_size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn normalized_body_size(&self) -> Option<usize> {
+        Some(normalized_len(self))
+    }
+
     fn into_mail_body(self, handle: &Handle) -> MailBody {
         let (sender, body) = MailBody::pair();
         handle.spawn(
@@ -239,7 +1065,429 @@ impl IntoMailBody for Vec<u8> {
 }
 
 impl IntoMailBody for String {
+    fn body_type(&self) -> Option<MailBodyParam> {
+        Some(detect_body_type(self.as_bytes()))
+    }
+
+    fn body_size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn normalized_body_size(&self) -> Option<usize> {
+        Some(normalized_len(self.as_bytes()))
+    }
+
     fn into_mail_body(self, handle: &Handle) -> MailBody {
         self.into_bytes().into_mail_body(handle)
     }
 }
+
+/// A message body with a `BODY` type given by the caller
+///
+/// This is useful for streamed bodies, which can't be inspected before they
+/// are sent.
+pub struct TypedBody<B>(pub MailBodyParam, pub B);
+
+impl<B: IntoMailBody> IntoMailBody for TypedBody<B> {
+    fn body_type(&self) -> Option<MailBodyParam> {
+        Some(self.0.clone())
+    }
+
+    fn body_size(&self) -> Option<usize> {
+        self.1.body_size()
+    }
+
+    fn normalized_body_size(&self) -> Option<usize> {
+        self.1.normalized_body_size()
+    }
+
+    fn into_mail_body(self, handle: &Handle) -> MailBody {
+        self.1.into_mail_body(handle)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use {DeliveryReport, Mailer, PoolConfig, RecipientStatus};
+    use client::{ClientTimeouts, ConnectionInfo, TlsNegotiation};
+    use ehlo::{EhloCapabilities};
+    use error::{SmtpError};
+    use futures::{future, stream, Future, Sink, Stream};
+    use request::{DsnNotify, MailBodyParam, MailParam, Mailbox, Request};
+    use response::{Response};
+    use server::{ServerParams, ServerProto};
+    use std::cell::{Cell, RefCell};
+    use std::cmp;
+    use std::io::{BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+    use std::net::{TcpListener as StdTcpListener};
+    use std::rc::{Rc};
+    use std::sync::{Arc};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+    use tokio_core::net::{TcpListener};
+    use tokio_core::reactor::{Core, Handle, Timeout};
+    use tokio_proto::{BindServer};
+    use tokio_proto::streaming::{Body, Message};
+    use tokio_proto::streaming::pipeline::{StreamingPipeline};
+    use tokio_service::{Service};
+    use super::{DsnParams, MAX_PIPELINED_CHUNKS, RetryPolicy, body_param, size_param};
+
+    pub type Log = Rc<RefCell<Vec<String>>>;
+    type Chunks = Vec<(String, Vec<u8>)>;
+
+    /// A server that logs requests, rejects senders and recipients at
+    /// `reject.test`, and refuses to reset a transaction.
+    ///
+    /// Recipients at `slow.test` are answered 100ms after the previous one,
+    /// and messages containing `stall` are never answered.
+    struct Peer {
+        ehlo: &'static str,
+        log: Log,
+        handle: Handle,
+        slow: Rc<Cell<Instant>>,
+    }
+
+    impl Service for Peer {
+        type Request = Message<Request, Body<Vec<u8>, IoError>>;
+        type Response = Message<Response, Body<(), IoError>>;
+        type Error = IoError;
+        type Future = Box<Future<Item = Self::Response, Error = IoError>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            let (request, body) = match req {
+                Message::WithoutBody(request) => (request, None),
+                Message::WithBody(request, body) => (request, Some(body)),
+            };
+            self.log.borrow_mut().push(request.to_string());
+            let reply = match request {
+                Request::Ehlo(_) => self.ehlo,
+                Request::Rcpt { ref to, .. } if to.to_string().ends_with("@reject.test>") => {
+                    "550 5.1.1 No such user\r\n"
+                },
+                Request::Mail { ref from, .. } if from.to_string().ends_with("@reject.test>") => {
+                    "550 5.7.1 Sender rejected\r\n"
+                },
+                Request::Rset => "502 5.5.1 Not implemented\r\n",
+                Request::Quit => "221 Bye\r\n",
+                _ => "250 OK\r\n",
+            };
+            let reply = Message::WithoutBody(reply.parse().unwrap());
+            match (request, body) {
+                (_, Some(body)) => {
+                    let log = self.log.clone();
+                    Box::new(body.concat2().and_then(move |body| {
+                        let body = String::from_utf8_lossy(&body).into_owned();
+                        let stall = body.contains("stall");
+                        log.borrow_mut().push(body);
+                        if stall {
+                            future::Either::A(future::empty())
+                        } else {
+                            future::Either::B(future::ok(reply))
+                        }
+                    }))
+                },
+                (Request::Rcpt { ref to, .. }, None) if to.to_string().ends_with("@slow.test>") => {
+                    let at = cmp::max(self.slow.get(), Instant::now()) + Duration::from_millis(100);
+                    self.slow.set(at);
+                    Box::new(Timeout::new_at(at, &self.handle).unwrap().map(move |()| reply))
+                },
+                (_, None) => Box::new(future::ok(reply)),
+            }
+        }
+    }
+
+    /// Serve `Peer` on a local port, and return its address.
+    pub fn serve(ehlo: &'static str, handle: &Handle) -> (String, Log) {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let log = Log::default();
+        let server_log = log.clone();
+        let server_handle = handle.clone();
+        handle.spawn(listener.incoming()
+            .for_each(move |(io, _)| {
+                let params = Arc::new(ServerParams { id: "mx.test".to_string() });
+                let peer = Peer {
+                    ehlo: ehlo,
+                    log: server_log.clone(),
+                    handle: server_handle.clone(),
+                    slow: Rc::new(Cell::new(Instant::now())),
+                };
+                BindServer::<StreamingPipeline<Body<(), IoError>>, _>
+                    ::bind_server(&ServerProto(params), &server_handle, io, peer);
+                Ok(())
+            })
+            .map_err(|_| ()));
+        (addr, log)
+    }
+
+    /// Serve a single connection with `CHUNKING` in a thread, and return its
+    /// address.
+    ///
+    /// Chunks are only answered once the client stops sending. The thread
+    /// returns every chunk, and the most chunks that were left unanswered.
+    fn serve_chunks() -> (String, JoinHandle<(Chunks, usize)>) {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (io, _) = listener.accept().unwrap();
+            let mut writer = io.try_clone().unwrap();
+            let mut reader = BufReader::new(io);
+            writer.write_all(b"220 mx.test\r\n").unwrap();
+            let mut chunks = vec![];
+            let (mut unanswered, mut most) = (0, 0);
+            loop {
+                let mut line = String::new();
+                reader.get_ref().set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+                match reader.read_line(&mut line) {
+                    Ok(_) => {},
+                    Err(ref err) if err.kind() == IoErrorKind::WouldBlock || err.kind() == IoErrorKind::TimedOut => {
+                        for _ in 0..unanswered {
+                            writer.write_all(b"250 OK\r\n").unwrap();
+                        }
+                        unanswered = 0;
+                        continue;
+                    },
+                    Err(err) => panic!("failed to read request: {}", err),
+                }
+                reader.get_ref().set_read_timeout(None).unwrap();
+                if line.starts_with("BDAT ") {
+                    let size = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+                    let mut chunk = vec![0; size];
+                    reader.read_exact(&mut chunk).unwrap();
+                    chunks.push((line, chunk));
+                    unanswered += 1;
+                    most = cmp::max(most, unanswered);
+                    continue;
+                }
+                let reply = match &line[..4] {
+                    "EHLO" => "250-mx.test\r\n250-CHUNKING\r\n250 PIPELINING\r\n",
+                    "QUIT" => "221 Bye\r\n",
+                    _ => "250 OK\r\n",
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+                if line.starts_with("QUIT") {
+                    return (chunks, most);
+                }
+            }
+        });
+        (addr, server)
+    }
+
+    /// The statuses of recipients, by index, with responses of the given
+    /// codes
+    pub fn statuses(recipients: &[Mailbox], codes: &[(usize, &str)]) -> Vec<RecipientStatus> {
+        codes.iter()
+            .map(|&(idx, code)| RecipientStatus {
+                recipient: recipients[idx].clone(),
+                response: format!("{} Status\r\n", code).parse().unwrap(),
+            })
+            .collect()
+    }
+
+    /// The result of a successful attempt, with the given recipient statuses
+    pub fn report(recipients: &[Mailbox], codes: &[(usize, &str)]) -> Result<DeliveryReport, SmtpError> {
+        Ok(DeliveryReport {
+            addr: "127.0.0.1:25".parse().unwrap(),
+            connection: ConnectionInfo {
+                greeting: "220 mx.test\r\n".parse().unwrap(),
+                ehlo_response: "250 mx.test\r\n".parse().unwrap(),
+                capabilities: EhloCapabilities::default(),
+                tls: TlsNegotiation::None,
+                esmtp: true,
+            },
+            recipients: statuses(recipients, codes),
+            data_response: "250 Queued\r\n".parse().unwrap(),
+        })
+    }
+
+    /// The result of an attempt where `DATA` was rejected with a code
+    pub fn rejected(code: &str) -> Result<DeliveryReport, SmtpError> {
+        Err(SmtpError::rejected(Request::Data, format!("{} No\r\n", code).parse().unwrap()))
+    }
+
+    #[test]
+    fn test() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (addr, log) = serve("250-mx.test\r\n250 PIPELINING\r\n", &handle);
+        let mailer = Mailer::builder(addr.clone()).build().unwrap();
+
+        // Deliver to the accepted recipients only.
+        let recipients = vec![
+            "alice@example.test".parse().unwrap(),
+            "bob@reject.test".parse().unwrap(),
+            "carol@example.test".parse().unwrap(),
+        ];
+        let report = core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                                          "Hello\r\n".to_string(), &handle)).unwrap();
+        let accepted = report.accepted().iter()
+            .map(|status| status.recipient.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(accepted, vec!["<alice@example.test>", "<carol@example.test>"]);
+        let rejected = report.rejected();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].recipient.to_string(), "<bob@reject.test>");
+        assert_eq!(rejected[0].response.to_string(), "550 5.1.1 No such user\r\n");
+        assert_eq!(log.borrow().iter().filter(|line| line.as_str() == "Hello\r\n").count(), 1);
+
+        // Fail without sending the message if no recipient is accepted.
+        log.borrow_mut().clear();
+        let recipients = vec![
+            "bob@reject.test".parse().unwrap(),
+            "dave@reject.test".parse().unwrap(),
+        ];
+        let err = core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                                       "Hello\r\n".to_string(), &handle)).unwrap_err();
+        match err {
+            SmtpError::NoRecipients(ref statuses) => {
+                assert_eq!(statuses.len(), 2);
+                assert!(statuses.iter().all(|status| !status.is_accepted()));
+            },
+            ref err => panic!("unexpected error: {}", err),
+        }
+        assert!(!err.is_transient());
+        assert!(log.borrow().iter().all(|line| line != "DATA\r\n" && line != "Hello\r\n"), "{:?}", log);
+
+        // Report the delivery error, not the failed reset.
+        let session = core.run(mailer.session(&handle)).unwrap();
+        let recipients = vec!["alice@example.test".parse().unwrap()];
+        let err = core.run(session.send("john@reject.test".parse().unwrap(), recipients,
+                                        "Hello\r\n".to_string(), &handle)).err().unwrap();
+        assert_eq!(err.to_string(), "MAIL FROM:<john@reject.test> rejected: 550 5.7.1 Sender rejected");
+
+        // Wait for pipelined responses one at a time, from when the previous
+        // one arrives.
+        let timeouts = ClientTimeouts {
+            command: Duration::from_millis(250),
+            data_termination: Duration::from_millis(250),
+            ..ClientTimeouts::default()
+        };
+        let mailer = Mailer::builder(addr).set_timeouts(timeouts).build().unwrap();
+        let recipients = (0..4)
+            .map(|idx| format!("user{}@slow.test", idx).parse().unwrap())
+            .collect();
+        let report = core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                                          "Hello\r\n".to_string(), &handle)).unwrap();
+        assert_eq!(report.accepted().len(), 4);
+        let recipients = vec!["alice@example.test".parse().unwrap()];
+        let err = core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                                       "stall\r\n".to_string(), &handle)).unwrap_err();
+        match err {
+            SmtpError::Timeout => {},
+            ref err => panic!("unexpected error: {}", err),
+        }
+
+        // A line ending held back until the end goes in the last chunk.
+        let (addr, server) = serve_chunks();
+        let mailer = Mailer::builder(addr).build().unwrap();
+        let recipients = vec!["alice@example.test".parse().unwrap()];
+        core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                             "a\r".to_string(), &handle)).unwrap();
+        let (chunks, _) = server.join().unwrap();
+        assert_eq!(chunks, vec![
+            ("BDAT 1\r\n".to_string(), b"a".to_vec()),
+            ("BDAT 2 LAST\r\n".to_string(), b"\r\n".to_vec()),
+        ]);
+
+        // Only so many chunks are sent ahead of their responses.
+        let (addr, server) = serve_chunks();
+        let mailer = Mailer::builder(addr).build().unwrap();
+        let recipients = vec!["alice@example.test".parse().unwrap()];
+        let (sender, body) = Body::pair();
+        let lines = (0..20).map(|idx| Ok(Ok(format!("line {}\r\n", idx).into_bytes())));
+        handle.spawn(sender.send_all(stream::iter_result(lines)).then(|_| Ok(())));
+        core.run(mailer.send("john@example.test".parse().unwrap(), recipients, body, &handle)).unwrap();
+        let (chunks, most) = server.join().unwrap();
+        assert_eq!(chunks.len(), 21);
+        assert_eq!(most, MAX_PIPELINED_CHUNKS);
+
+        // Choose the `BODY` parameter by what the server supports.
+        let plain = EhloCapabilities::default();
+        let eight_bit = EhloCapabilities { eight_bit_mime: true, ..EhloCapabilities::default() };
+        let binary = EhloCapabilities { binary_mime: true, ..eight_bit.clone() };
+        let chunking = EhloCapabilities { chunking: true, ..binary.clone() };
+        for (body_type, capabilities, expect) in vec![
+            (None, &plain, Ok(None)),
+            (None, &eight_bit, Ok(Some(MailBodyParam::EightBitMime))),
+            (Some(MailBodyParam::SevenBit), &plain, Ok(None)),
+            (Some(MailBodyParam::SevenBit), &eight_bit, Ok(Some(MailBodyParam::SevenBit))),
+            (Some(MailBodyParam::EightBitMime), &plain, Err(MailBodyParam::EightBitMime)),
+            (Some(MailBodyParam::EightBitMime), &eight_bit, Ok(Some(MailBodyParam::EightBitMime))),
+            (Some(MailBodyParam::BinaryMime), &eight_bit, Err(MailBodyParam::BinaryMime)),
+            (Some(MailBodyParam::BinaryMime), &binary, Err(MailBodyParam::BinaryMime)),
+            (Some(MailBodyParam::BinaryMime), &chunking, Ok(Some(MailBodyParam::BinaryMime))),
+        ] {
+            let result = body_param(body_type, capabilities).map_err(|err| match err {
+                SmtpError::BodyTypeUnsupported(body_type) => body_type,
+                err => panic!("unexpected error: {}", err),
+            });
+            assert_eq!(result, expect);
+        }
+
+        // Send the original recipient as plain ASCII, whatever its address.
+        let dsn = DsnParams {
+            notify: Some(DsnNotify { failure: true, ..DsnNotify::default() }),
+            original_recipients: true,
+            ..DsnParams::default()
+        };
+        for (recipient, expect) in vec![
+            ("<>", "NOTIFY=FAILURE"),
+            ("a+b@example.test", "NOTIFY=FAILURE ORCPT=rfc822;a+2Bb@example.test"),
+            ("bj\u{f8}rn@b\u{fc}cher.example", "NOTIFY=FAILURE ORCPT=utf-8;bj\\x{F8}rn@b\\x{FC}cher.example"),
+            ("john@b\u{fc}cher.example", "NOTIFY=FAILURE ORCPT=utf-8;john@b\\x{FC}cher.example"),
+        ] {
+            let params = dsn.rcpt_params(&recipient.parse::<Mailbox>().unwrap()).iter()
+                .map(|param| param.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(params.join(" "), expect);
+        }
+
+        // Declare the size if the server supports it, and refuse messages
+        // over its limit.
+        for (size, limit, expect) in vec![
+            (None, None, Ok(None)),
+            (None, Some(100), Ok(None)),
+            (Some(200), None, Ok(None)),
+            (Some(100), Some(100), Ok(Some(MailParam::Size(100)))),
+            (Some(200), Some(0), Ok(Some(MailParam::Size(200)))),
+            (Some(200), Some(100), Err((200, 100))),
+        ] {
+            let result = size_param(size, limit).map_err(|err| match err {
+                SmtpError::MessageTooLarge { size, limit } => (size, limit),
+                err => panic!("unexpected error: {}", err),
+            });
+            assert_eq!(result, expect);
+        }
+
+        // The size is declared as sent, with line endings converted.
+        let (addr, log) = serve("250-mx.test\r\n250 SIZE 1000\r\n", &handle);
+        let mailer = Mailer::builder(addr).build().unwrap();
+        let recipients = vec!["alice@example.test".parse().unwrap()];
+        core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                             "a\nb\n".to_string(), &handle)).unwrap();
+        assert!(log.borrow().iter().any(|line| line == "MAIL FROM:<john@example.test> SIZE=6\r\n"), "{:?}", log);
+
+        // A body of unknown type turns out to need `8BITMIME` while it is
+        // sent.
+        let (addr, _) = serve("250 mx.test\r\n", &handle);
+        let mailer = Mailer::builder(addr).build().unwrap();
+        let recipients = vec!["alice@example.test".parse().unwrap()];
+        let (sender, body) = Body::pair();
+        let lines = vec![Ok(Ok(b"Hej,\r\n".to_vec())), Ok(Ok("Bj\u{f8}rn\r\n".as_bytes().to_vec()))];
+        handle.spawn(sender.send_all(stream::iter_result(lines)).then(|_| Ok(())));
+        let err = core.run(mailer.send("john@example.test".parse().unwrap(), recipients, body, &handle))
+            .unwrap_err();
+        match err {
+            SmtpError::BodyTypeUnsupported(MailBodyParam::EightBitMime) => {},
+            ref err => panic!("unexpected error: {}", err),
+        }
+
+        let config = PoolConfig { max_size: 0, ..PoolConfig::default() };
+        let err = Mailer::builder("127.0.0.1:25".to_string()).set_pool(config).build().err().unwrap();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+        let policy = RetryPolicy { max_attempts: 0, ..RetryPolicy::default() };
+        let err = Mailer::builder("127.0.0.1:25".to_string()).set_retry_policy(policy).build().err().unwrap();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,513 @@
+//! Direct delivery to the mail exchangers of recipient domains
+//!
+//! Recipients are grouped by domain, and each domain is delivered to
+//! separately. Mail exchangers are found through a `Resolver`, following
+//! RFC 5321, section 5.1: hosts are tried in order of preference, and
+//! a domain without MX records is its own mail exchanger.
+
+use client::{ClientParams, ClientSecurity, ClientTimeouts, ClientTlsParams};
+use error::{SmtpError};
+use futures::{future, Future};
+use futures::future::{Loop};
+use native_tls::{TlsConnector};
+use rand::{self, Rng};
+use request::{ClientId, Mailbox};
+use std::collections::{HashMap};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc};
+use tokio_core::reactor::{Handle};
+use util::{domain_to_ascii, interleave_families, with_timeout};
+use super::{connect_any, is_unavailable, MessageInfo, DeliveryReport, IntoMailBody, Session};
+
+
+/// A mail exchanger, from an MX record
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct MxRecord {
+    /// Lower values are preferred
+    pub preference: u16,
+    /// The host name of the mail exchanger
+    pub exchange: String,
+}
+
+
+/// Looks up mail exchangers and host addresses
+pub trait Resolver {
+    /// Look up the MX records of a domain.
+    ///
+    /// A domain without MX records results in an empty list.
+    fn lookup_mx(&self, domain: &str) -> Box<Future<Item = Vec<MxRecord>, Error = IoError>>;
+
+    /// Look up the addresses of a host.
+    fn lookup_host(&self, host: &str) -> Box<Future<Item = Vec<IpAddr>, Error = IoError>>;
+}
+
+
+/// A resolver using the system resolver, through the standard library
+///
+/// The standard library can't look up MX records, so MX lookups fail, and
+/// only delivery to address literals works. Host lookups block the event
+/// loop.
+#[derive(Copy,Clone,Debug,Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup_mx(&self, _: &str) -> Box<Future<Item = Vec<MxRecord>, Error = IoError>> {
+        // Treating every domain as having no MX records would silently
+        // deliver to the wrong hosts.
+        Box::new(future::err(IoError::new(IoErrorKind::Other, "MX lookup unsupported")))
+    }
+
+    fn lookup_host(&self, host: &str) -> Box<Future<Item = Vec<IpAddr>, Error = IoError>> {
+        Box::new(future::result((host, 0).to_socket_addrs()
+            .map(|addrs| addrs.map(|addr| addr.ip()).collect())))
+    }
+}
+
+
+/// A resolver with fixed records, for testing or static routing
+#[derive(Clone,Debug,Default)]
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<MxRecord>>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    /// Create a resolver without any records.
+    pub fn new() -> Self {
+        StaticResolver::default()
+    }
+
+    /// Add an MX record for a domain.
+    pub fn add_mx(mut self, domain: String, preference: u16, exchange: String) -> Self {
+        self.mx.entry(domain.to_ascii_lowercase()).or_default().push(MxRecord {
+            preference: preference,
+            exchange: exchange,
+        });
+        self
+    }
+
+    /// Add an address for a host.
+    pub fn add_host(mut self, host: String, addr: IpAddr) -> Self {
+        self.hosts.entry(host.to_ascii_lowercase()).or_default().push(addr);
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn lookup_mx(&self, domain: &str) -> Box<Future<Item = Vec<MxRecord>, Error = IoError>> {
+        Box::new(future::ok(self.mx.get(&domain.to_ascii_lowercase())
+            .cloned().unwrap_or_default()))
+    }
+
+    fn lookup_host(&self, host: &str) -> Box<Future<Item = Vec<IpAddr>, Error = IoError>> {
+        Box::new(future::result(self.hosts.get(&host.to_ascii_lowercase())
+            .cloned()
+            .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "host not found"))))
+    }
+}
+
+
+/// The result of delivering to the recipients in a single domain
+#[derive(Debug)]
+pub struct DomainDelivery {
+    /// The domain, in lowercase
+    pub domain: String,
+    /// The recipients in this domain
+    pub recipients: Vec<Mailbox>,
+    /// The result of the delivery
+    pub result: Result<DeliveryReport, SmtpError>,
+}
+
+
+struct MxMailerParams {
+    resolver: Arc<Resolver + Send + Sync>,
+    port: u16,
+    client_id: ClientId,
+    tls_connector: Option<TlsConnector>,
+    require_esmtp: bool,
+    timeouts: ClientTimeouts,
+    normalize_line_endings: bool,
+}
+
+impl MxMailerParams {
+    fn client_params(&self, host: &str) -> Arc<ClientParams> {
+        Arc::new(ClientParams {
+            id: self.client_id.clone(),
+            security: match self.tls_connector {
+                None => ClientSecurity::None,
+                Some(ref connector) => ClientSecurity::Optional(ClientTlsParams {
+                    connector: connector.clone(),
+                    sni_domain: host.to_string(),
+                }),
+            },
+            auth: None,
+            require_esmtp: self.require_esmtp,
+            timeouts: self.timeouts,
+            normalize_line_endings: self.normalize_line_endings,
+        })
+    }
+}
+
+
+/// Object used to send mail directly to the mail exchangers of recipients.
+///
+/// An `MxMailer` is created using an `MxMailerBuilder`.
+#[derive(Clone)]
+pub struct MxMailer(Arc<MxMailerParams>);
+
+impl MxMailer {
+    /// Alias for `MxMailerBuilder::new()`.
+    pub fn builder<R>(resolver: R) -> MxMailerBuilder
+            where R: Resolver + Send + Sync + 'static {
+        MxMailerBuilder::new(resolver)
+    }
+
+    /// Send an email to every recipient domain.
+    ///
+    /// Domains are delivered to concurrently, each over its own connection.
+    /// The future itself doesn't fail; the result for each domain is
+    /// reported separately, in the order domains first appear in the
+    /// recipients.
+    pub fn send<B>(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: B, handle: &Handle)
+            -> Box<Future<Item = Vec<DomainDelivery>, Error = SmtpError>>
+            where B: IntoMailBody + Clone + 'static {
+        let deliveries = group_by_domain(recipients).into_iter()
+            .map(|(domain, recipients)| {
+                let return_path = return_path.clone();
+                let message = MessageInfo::of(&body, None);
+                let body = body.clone().into_mail_body(handle);
+                let handle = handle.clone();
+                let delivery = recipients.clone();
+                self.route(&domain, handle.clone())
+                    .and_then(move |session| {
+                        session.send_and_close(return_path, delivery, body, message, &handle)
+                    })
+                    .then(move |result| {
+                        Ok(DomainDelivery {
+                            domain: domain,
+                            recipients: recipients,
+                            result: result,
+                        })
+                    })
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(deliveries))
+    }
+
+    /// Connect to the most preferred mail exchanger of a domain that
+    /// accepts us.
+    fn route(&self, domain: &str, handle: Handle) -> Box<Future<Item = Session, Error = SmtpError>> {
+        let params = self.0.clone();
+        if let Some(ip) = address_literal(domain) {
+            let addr = SocketAddr::new(ip, params.port);
+            return connect_any(vec![addr], params.client_params(&ip.to_string()), &handle);
+        }
+
+        // Internationalized domains are looked up in their ASCII form.
+        let domain = match domain_to_ascii(domain) {
+            Some(domain) => domain,
+            None => return Box::new(future::err(SmtpError::NoMailExchanger(domain.to_string()))),
+        };
+        // Lookups are subject to the connect timeout.
+        let lookup = params.resolver.lookup_mx(&domain);
+        Box::new(with_timeout(lookup, params.timeouts.connect, Some(&handle))
+            .map_err(SmtpError::from)
+            .and_then(move |records| exchangers(&domain, records))
+            .and_then(move |hosts| {
+                future::loop_fn(0, move |idx| {
+                    let host = hosts[idx].clone();
+                    let host_name = host.clone();
+                    let is_last = idx + 1 == hosts.len();
+                    let params = params.clone();
+                    let handle = handle.clone();
+                    let lookup = params.resolver.lookup_host(&host);
+                    with_timeout(lookup, params.timeouts.connect, Some(&handle))
+                        .map_err(SmtpError::from)
+                        .and_then(move |ips| {
+                            let addrs = interleave_families(ips.into_iter()
+                                .map(|ip| SocketAddr::new(ip, params.port))
+                                .collect());
+                            if addrs.is_empty() {
+                                return future::Either::A(future::err(SmtpError::Io(
+                                    IoError::new(IoErrorKind::NotFound, "no addresses for host"))));
+                            }
+                            future::Either::B(connect_any(addrs, params.client_params(&host), &handle))
+                        })
+                        .then(move |result| {
+                            match result {
+                                Ok(session) => Ok(Loop::Break(session)),
+                                Err(ref err) if !is_last && is_unavailable(err) => {
+                                    debug!("failed to connect to {}: {}", host_name, err);
+                                    Ok(Loop::Continue(idx + 1))
+                                },
+                                Err(err) => Err(err),
+                            }
+                        })
+                })
+            }))
+    }
+}
+
+/// Group recipients by lowercased domain, in order of first appearance.
+///
+/// The null mailbox ends up in a group with an empty domain.
+fn group_by_domain(recipients: Vec<Mailbox>) -> Vec<(String, Vec<Mailbox>)> {
+    let mut groups: Vec<(String, Vec<Mailbox>)> = vec![];
+    for recipient in recipients {
+        let domain = recipient.0.as_ref()
+            .map_or(String::new(), |addr| addr.domain.to_ascii_lowercase());
+        match groups.iter().position(|&(ref other, _)| *other == domain) {
+            Some(idx) => groups[idx].1.push(recipient),
+            None => groups.push((domain, vec![recipient])),
+        }
+    }
+    groups
+}
+
+/// Parse a domain that is an address literal, e.g. `[192.0.2.1]`.
+fn address_literal(domain: &str) -> Option<IpAddr> {
+    if !domain.starts_with('[') || !domain.ends_with(']') {
+        return None;
+    }
+    let literal = &domain[1..domain.len() - 1];
+    if literal.len() > 5 && literal[..5].eq_ignore_ascii_case("IPv6:") {
+        literal[5..].parse().ok().map(IpAddr::V6)
+    } else {
+        literal.parse().ok().map(IpAddr::V4)
+    }
+}
+
+/// The hosts to try for a domain, in order.
+///
+/// Hosts with equal preference are shuffled. A domain without MX records is
+/// its own mail exchanger, while a null MX (RFC 7505) means the domain
+/// doesn't accept mail.
+fn exchangers(domain: &str, mut records: Vec<MxRecord>) -> Result<Vec<String>, SmtpError> {
+    if domain.is_empty() {
+        return Err(SmtpError::NoMailExchanger(domain.to_string()));
+    }
+    if records.is_empty() {
+        return Ok(vec![domain.to_string()]);
+    }
+    if records.iter().any(|record| record.exchange == "." || record.exchange.is_empty()) {
+        return Err(SmtpError::NoMailExchanger(domain.to_string()));
+    }
+
+    rand::thread_rng().shuffle(&mut records);
+    records.sort_by_key(|record| record.preference);
+    Ok(records.into_iter()
+        .map(|record| record.exchange.trim_end_matches('.').to_string())
+        .collect())
+}
+
+
+/// Builder for an `MxMailer` instance.
+pub struct MxMailerBuilder {
+    resolver: Arc<Resolver + Send + Sync>,
+    port: u16,
+    client_id: ClientId,
+    tls_connector: Option<TlsConnector>,
+    require_esmtp: bool,
+    timeouts: ClientTimeouts,
+    normalize_line_endings: bool,
+}
+
+impl MxMailerBuilder {
+    /// Create a builder, with the resolver used to find mail exchangers.
+    pub fn new<R>(resolver: R) -> Self
+            where R: Resolver + Send + Sync + 'static {
+        MxMailerBuilder {
+            resolver: Arc::new(resolver),
+            port: 25,
+            client_id: ClientId::Domain("localhost".to_string()),
+            tls_connector: None,
+            require_esmtp: false,
+            timeouts: ClientTimeouts::default(),
+            normalize_line_endings: true,
+        }
+    }
+
+    /// Set the port to connect to.
+    ///
+    /// By default, this is 25.
+    pub fn set_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Set the `EHLO` identifier to send.
+    ///
+    /// By default, this is `localhost`.
+    pub fn set_client_id(mut self, client_id: ClientId) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    /// Use `STARTTLS` if the server supports it, with the given connector.
+    ///
+    /// The name of the mail exchanger is used to verify its certificate. By
+    /// default, connections do not use TLS.
+    pub fn set_tls_connector(mut self, tls_connector: TlsConnector) -> Self {
+        self.tls_connector = Some(tls_connector);
+        self
+    }
+
+    /// Require servers to support ESMTP.
+    ///
+    /// By default, `HELO` is sent if a server rejects `EHLO`.
+    pub fn set_require_esmtp(mut self, require: bool) -> Self {
+        self.require_esmtp = require;
+        self
+    }
+
+    /// Set how long to wait for servers, and for lookups.
+    ///
+    /// Lookups are subject to the connect timeout. By default, the timeouts
+    /// from RFC 5321 are used.
+    pub fn set_timeouts(mut self, timeouts: ClientTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Convert bare `CR` and `LF` in message bodies to `CRLF`.
+    ///
+    /// By default, line endings are converted.
+    pub fn set_normalize_line_endings(mut self, normalize: bool) -> Self {
+        self.normalize_line_endings = normalize;
+        self
+    }
+
+    /// Transform this builder into an `MxMailer`.
+    pub fn build(self) -> MxMailer {
+        MxMailer(Arc::new(MxMailerParams {
+            resolver: self.resolver,
+            port: self.port,
+            client_id: self.client_id,
+            tls_connector: self.tls_connector,
+            require_esmtp: self.require_esmtp,
+            timeouts: self.timeouts,
+            normalize_line_endings: self.normalize_line_endings,
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use error::{SmtpError};
+    use futures::{Future};
+    use mx::{MxMailer, MxRecord, Resolver, StaticResolver, address_literal, exchangers, group_by_domain};
+    use request::{Mailbox};
+    use std::io::{Error as IoError};
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use tests::{serve};
+    use tokio_core::reactor::{Core};
+
+    /// A `StaticResolver` that logs the hosts it looks up
+    struct Logged(StaticResolver, Arc<Mutex<Vec<String>>>);
+
+    impl Resolver for Logged {
+        fn lookup_mx(&self, domain: &str) -> Box<Future<Item = Vec<MxRecord>, Error = IoError>> {
+            self.0.lookup_mx(domain)
+        }
+
+        fn lookup_host(&self, host: &str) -> Box<Future<Item = Vec<IpAddr>, Error = IoError>> {
+            self.1.lock().unwrap().push(host.to_string());
+            self.0.lookup_host(host)
+        }
+    }
+
+    #[test]
+    fn test() {
+        let record = |preference, exchange: &str| MxRecord {
+            preference: preference,
+            exchange: exchange.to_string(),
+        };
+
+        for (domain, records, expect) in vec![
+            ("example.test", vec![], Some(vec!["example.test"])),
+            ("example.test", vec![
+                record(20, "mx2.example.test."),
+                record(10, "mx1.example.test."),
+                record(30, "mx3.example.test"),
+            ], Some(vec!["mx1.example.test", "mx2.example.test", "mx3.example.test"])),
+            ("example.test", vec![record(0, ".")], None),
+            ("", vec![], None),
+        ] {
+            let expect = expect.map(|hosts: Vec<&str>| {
+                hosts.into_iter().map(|s| s.to_string()).collect::<Vec<_>>()
+            });
+            assert_eq!(exchangers(domain, records).ok(), expect);
+        }
+
+        // Equal preference is shuffled, but still comes before the rest.
+        let hosts = exchangers("example.test", vec![
+            record(20, "c"), record(10, "a"), record(10, "b"),
+        ]).unwrap();
+        assert_eq!(hosts[2], "c");
+
+        for (input, expect) in vec![
+            ("[192.0.2.1]", Some("192.0.2.1")),
+            ("[IPv6:2001:db8::1]", Some("2001:db8::1")),
+            ("[2001:db8::1]", None),
+            ("example.test", None),
+        ] {
+            assert_eq!(address_literal(input), expect.map(|s| s.parse().unwrap()));
+        }
+
+        let recipients = vec!["a@one.test", "b@TWO.test", "c@One.test", ""].into_iter()
+            .map(|s| s.parse::<Mailbox>().unwrap())
+            .collect();
+        let groups = group_by_domain(recipients).into_iter()
+            .map(|(domain, recipients)| (domain, recipients.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec![
+            ("one.test".to_string(), 2),
+            ("two.test".to_string(), 1),
+            ("".to_string(), 1),
+        ]);
+
+        // Deliver to a local server. The most preferred exchanger has no
+        // addresses, so the next one is tried.
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (addr, log) = serve("250 mx.test\r\n", &handle);
+        let addr = addr.parse::<SocketAddr>().unwrap();
+        let resolver = StaticResolver::new()
+            .add_mx("example.test".to_string(), 20, "mx2.example.test.".to_string())
+            .add_mx("example.test".to_string(), 10, "mx1.example.test".to_string())
+            .add_mx("example.test".to_string(), 30, "mx3.example.test".to_string())
+            .add_mx("null.test".to_string(), 0, ".".to_string())
+            .add_host("mx2.example.test".to_string(), addr.ip())
+            .add_host("mx3.example.test".to_string(), addr.ip())
+            .add_host("other.test".to_string(), addr.ip());
+        let lookups = Arc::new(Mutex::new(vec![]));
+        let mailer = MxMailer::builder(Logged(resolver, lookups.clone())).set_port(addr.port()).build();
+        let recipients = vec!["a@example.test", "b@null.test", "c@Other.test", "d@EXAMPLE.test"].into_iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let deliveries = core.run(mailer.send("john@example.test".parse().unwrap(), recipients,
+                                              "Hello\r\n".to_string(), &handle)).unwrap();
+        let results = deliveries.iter()
+            .map(|delivery| (delivery.domain.as_str(), delivery.recipients.len(), match delivery.result {
+                Ok(ref report) => report.accepted().len().to_string(),
+                Err(SmtpError::NoMailExchanger(ref domain)) => format!("no mail exchanger for {}", domain),
+                Err(ref err) => err.to_string(),
+            }))
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec![
+            ("example.test", 2, "2".to_string()),
+            ("null.test", 1, "no mail exchanger for null.test".to_string()),
+            ("other.test", 1, "1".to_string()),
+        ]);
+        assert_eq!(*lookups.lock().unwrap(), vec!["mx1.example.test", "mx2.example.test", "other.test"]);
+        let log = log.borrow();
+        assert_eq!(log.iter().filter(|line| line.starts_with("MAIL FROM:")).count(), 2);
+        assert_eq!(log.iter().filter(|line| line.as_str() == "Hello\r\n").count(), 2);
+        for rcpt in &["RCPT TO:<a@example.test>\r\n", "RCPT TO:<c@Other.test>\r\n", "RCPT TO:<d@EXAMPLE.test>\r\n"] {
+            assert!(log.iter().any(|line| line == rcpt), "{:?}", log);
+        }
+    }
+}
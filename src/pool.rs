@@ -0,0 +1,415 @@
+//! A pool of idle sessions, shared by all sends of a `Mailer`
+//!
+//! Connections count towards the maximum from the moment they are dialed
+//! until they are closed. When the maximum is reached, sends wait for
+//! a connection to be returned to the pool. A checked out connection is
+//! held by a `Slot`, so it stops counting if a send is dropped half way.
+
+use error::{SmtpError};
+use futures::{future, Async, Future, Poll};
+use futures::future::{Loop};
+use futures::sync::oneshot;
+use std::collections::{VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant};
+use tokio_core::reactor::{Handle, Timeout};
+use super::{PoolConfig, Session};
+
+
+type Connect = Box<Future<Item = Session, Error = SmtpError>>;
+type Checkout = Box<Future<Item = (Session, Slot), Error = SmtpError>>;
+
+
+struct Idle<S> {
+    key: u64,
+    since: Instant,
+    session: S,
+}
+
+/// The bookkeeping of a pool, apart from opening and closing connections
+struct PoolState<S> {
+    idle: Vec<Idle<S>>,
+    open: usize,
+    waiters: VecDeque<oneshot::Sender<Option<S>>>,
+    next_key: u64,
+}
+
+enum Take<S> {
+    Idle(Box<S>),
+    Connect,
+    Wait(oneshot::Receiver<Option<S>>),
+}
+
+impl<S> PoolState<S> {
+    fn new() -> Self {
+        PoolState {
+            idle: vec![],
+            open: 0,
+            waiters: VecDeque::new(),
+            next_key: 0,
+        }
+    }
+
+    /// Take an idle session, or make room to dial, or wait in line.
+    ///
+    /// Also returns the sessions that were idle for too long, which are no
+    /// longer counted and should be closed.
+    fn take(&mut self, config: &PoolConfig, now: Instant) -> (Take<S>, Vec<S>) {
+        // Timers may not have run yet, if the event loop was not running.
+        let idle_timeout = config.idle_timeout;
+        let (expired, idle) = self.idle.drain(..)
+            .partition::<Vec<_>, _>(|idle| now.duration_since(idle.since) >= idle_timeout);
+        self.idle = idle;
+        self.open -= expired.len();
+        let expired = expired.into_iter().map(|idle| idle.session).collect();
+
+        // Prefer the most recently used session.
+        let take = if let Some(idle) = self.idle.pop() {
+            Take::Idle(Box::new(idle.session))
+        } else if self.open < config.max_size {
+            self.open += 1;
+            Take::Connect
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            self.waiters.push_back(sender);
+            Take::Wait(receiver)
+        };
+        (take, expired)
+    }
+
+    /// Hand a returned session to the first waiter, or keep it idle.
+    ///
+    /// Returns the key of the idle session, to expire it with.
+    fn checkin(&mut self, session: S, now: Instant) -> Option<u64> {
+        let mut session = Some(session);
+        while let Some(waiter) = self.waiters.pop_front() {
+            match waiter.send(session.take()) {
+                Ok(()) => return None,
+                Err(returned) => session = returned,
+            }
+        }
+        let key = self.next_key;
+        self.next_key += 1;
+        self.idle.push(Idle {
+            key: key,
+            since: now,
+            session: session.take().expect("session to return"),
+        });
+        Some(key)
+    }
+
+    /// Remove a session that is still idle, to close it.
+    ///
+    /// The session is still counted until it is released.
+    fn expire(&mut self, key: u64) -> Option<S> {
+        self.idle.iter().position(|idle| idle.key == key)
+            .map(|idx| self.idle.remove(idx).session)
+    }
+
+    /// Stop counting a connection, and make room for the first waiter.
+    fn release(&mut self) {
+        self.open -= 1;
+        self.notify();
+    }
+
+    /// Tell the first waiter there is room to dial.
+    fn notify(&mut self) {
+        while let Some(waiter) = self.waiters.pop_front() {
+            if waiter.send(None).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+/// Tells if a session has sent the maximum number of messages.
+fn is_spent(config: &PoolConfig, transactions: usize) -> bool {
+    config.max_messages.map_or(false, |max| transactions >= max)
+}
+
+
+#[derive(Clone)]
+pub struct Pool {
+    config: PoolConfig,
+    state: Arc<Mutex<PoolState<Session>>>,
+}
+
+impl Pool {
+    pub fn new(config: PoolConfig) -> Self {
+        Pool {
+            config: config,
+            state: Arc::new(Mutex::new(PoolState::new())),
+        }
+    }
+
+    /// Take a healthy session from the pool, or dial a new one using
+    /// `connect` if there is room.
+    ///
+    /// The session comes with the `Slot` it is counted in, to check it in
+    /// with after use.
+    pub fn checkout<F>(&self, connect: F, handle: &Handle) -> Checkout
+            where F: Fn() -> Connect + 'static {
+        let pool = self.clone();
+        let handle = handle.clone();
+        Box::new(future::loop_fn((), move |()| {
+            let pool = pool.clone();
+            let step: Box<Future<Item = Loop<(Session, Slot), ()>, Error = SmtpError>> = match pool.take(&handle) {
+                Take::Idle(session) => {
+                    let slot = Slot { pool: Some(pool) };
+                    Box::new(session.noop().then(move |result| {
+                        match result {
+                            Ok(session) => Ok(Loop::Break((session, slot))),
+                            Err(err) => {
+                                debug!("discarding pooled connection: {}", err);
+                                Ok(Loop::Continue(()))
+                            },
+                        }
+                    }))
+                },
+                Take::Connect => {
+                    let slot = Slot { pool: Some(pool) };
+                    Box::new(connect().map(move |session| Loop::Break((session, slot))))
+                },
+                Take::Wait(receiver) => {
+                    // A returned session is handed over directly. Otherwise,
+                    // a connection was closed, and there is room to dial.
+                    let waiter = Waiter {
+                        receiver: receiver,
+                        pool: pool,
+                        handle: handle.clone(),
+                    };
+                    Box::new(waiter.then(|result| {
+                        match result {
+                            Ok(Some((session, slot))) => Ok(Loop::Break((session, slot))),
+                            _ => Ok(Loop::Continue(())),
+                        }
+                    }))
+                },
+            };
+            step
+        }))
+    }
+
+    /// Return a session to the pool after use.
+    fn checkin(&self, session: Session, handle: &Handle) {
+        if is_spent(&self.config, session.transactions()) {
+            close(session, handle);
+            self.release();
+            return;
+        }
+
+        let key = match self.state.lock().unwrap().checkin(session, Instant::now()) {
+            Some(key) => key,
+            None => return,
+        };
+
+        // Close the session once it has been idle for too long.
+        if let Ok(timeout) = Timeout::new(self.config.idle_timeout, handle) {
+            let pool = self.clone();
+            let handle = handle.clone();
+            handle.clone().spawn(timeout.then(move |_| {
+                let expired = pool.state.lock().unwrap().expire(key);
+                if let Some(session) = expired {
+                    close(session, &handle);
+                    pool.release();
+                }
+                Ok(())
+            }));
+        }
+    }
+
+    /// Account for a checked out session that was lost.
+    fn release(&self) {
+        self.state.lock().unwrap().release();
+    }
+
+    fn take(&self, handle: &Handle) -> Take<Session> {
+        let (take, expired) = self.state.lock().unwrap().take(&self.config, Instant::now());
+        for session in expired {
+            close(session, handle);
+        }
+        take
+    }
+}
+
+fn close(session: Session, handle: &Handle) {
+    handle.spawn(session.close().then(|_| Ok(())));
+}
+
+
+/// A checked out connection, counted until it is dropped or checked in
+pub struct Slot {
+    pool: Option<Pool>,
+}
+
+impl Slot {
+    /// Return the session to the pool after use.
+    pub fn checkin(mut self, session: Session, handle: &Handle) {
+        if let Some(pool) = self.pool.take() {
+            pool.checkin(session, handle);
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.release();
+        }
+    }
+}
+
+
+/// Waits in line for a session, or for room to dial
+///
+/// If it is dropped after its turn came, the session or the room goes to
+/// the next in line.
+struct Waiter {
+    receiver: oneshot::Receiver<Option<Session>>,
+    pool: Pool,
+    handle: Handle,
+}
+
+impl Future for Waiter {
+    type Item = Option<(Session, Slot)>;
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let pool = &self.pool;
+        Ok(Async::Ready(try_ready!(self.receiver.poll())
+            .map(|session| (session, Slot { pool: Some(pool.clone()) }))))
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.receiver.close();
+        match self.receiver.try_recv() {
+            Ok(Some(Some(session))) => self.pool.checkin(session, &self.handle),
+            Ok(Some(None)) => self.pool.state.lock().unwrap().notify(),
+            _ => {},
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use pool::{Connect, Pool, PoolState, Take, is_spent};
+    use std::cell::{Cell};
+    use std::rc::{Rc};
+    use std::time::{Duration, Instant};
+    use tokio_core::reactor::{Core};
+    use super::super::{PoolConfig};
+
+    #[test]
+    fn test() {
+        let config = PoolConfig {
+            max_size: 2,
+            idle_timeout: Duration::from_secs(60),
+            max_messages: Some(3),
+        };
+        let now = Instant::now();
+        let mut state = PoolState::<&str>::new();
+
+        // Dial until the maximum is reached, then wait.
+        for _ in 0..2 {
+            match state.take(&config, now) {
+                (Take::Connect, ref expired) if expired.is_empty() => {},
+                _ => panic!("expected to dial"),
+            }
+        }
+        assert_eq!(state.open, 2);
+        let first = match state.take(&config, now) {
+            (Take::Wait(receiver), _) => receiver,
+            _ => panic!("expected to wait"),
+        };
+        let second = match state.take(&config, now) {
+            (Take::Wait(receiver), _) => receiver,
+            _ => panic!("expected to wait"),
+        };
+
+        // Returned sessions go to the first waiter, and lost ones make room.
+        assert_eq!(state.checkin("a", now), None);
+        assert_eq!(first.wait(), Ok(Some("a")));
+        state.release();
+        assert_eq!(second.wait(), Ok(None));
+        assert_eq!(state.open, 1);
+
+        // Waiters that gave up are skipped.
+        match state.take(&config, now) {
+            (Take::Connect, _) => {},
+            _ => panic!("expected to dial"),
+        }
+        match state.take(&config, now) {
+            (Take::Wait(receiver), _) => drop(receiver),
+            _ => panic!("expected to wait"),
+        }
+        let key = state.checkin("b", now).unwrap();
+        assert!(state.waiters.is_empty());
+
+        // The most recently returned session is taken first.
+        state.checkin("c", now + Duration::from_secs(30)).unwrap();
+        match state.take(&config, now + Duration::from_secs(30)) {
+            (Take::Idle(session), ref expired) if expired.is_empty() => assert_eq!(*session, "c"),
+            _ => panic!("expected an idle session"),
+        }
+        state.checkin("c", now + Duration::from_secs(30)).unwrap();
+
+        // Sessions idle for too long are closed, and no longer counted.
+        match state.take(&config, now + Duration::from_secs(60)) {
+            (Take::Idle(session), expired) => {
+                assert_eq!(*session, "c");
+                assert_eq!(expired, vec!["b"]);
+            },
+            _ => panic!("expected an idle session"),
+        }
+        assert_eq!(state.open, 1);
+        assert_eq!(state.expire(key), None);
+        let key = state.checkin("c", now + Duration::from_secs(60)).unwrap();
+        assert_eq!(state.expire(key), Some("c"));
+        state.release();
+        assert_eq!(state.open, 0);
+        assert!(state.idle.is_empty());
+
+        for (max_messages, transactions, expect) in vec![
+            (Some(3), 2, false),
+            (Some(3), 3, true),
+            (None, 1000, false),
+        ] {
+            let config = PoolConfig { max_messages: max_messages, ..config.clone() };
+            assert_eq!(is_spent(&config, transactions), expect);
+        }
+
+        // Dropped checkouts don't keep counting, and pass their turn on.
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let pool = Pool::new(PoolConfig { max_size: 1, ..config });
+        let dials = Rc::new(Cell::new(0));
+        let mut start = || {
+            let dials = dials.clone();
+            let mut checkout = pool.checkout(move || -> Connect {
+                dials.set(dials.get() + 1);
+                Box::new(future::empty())
+            }, &handle);
+            core.run(future::lazy(|| {
+                assert!(checkout.poll().unwrap().is_not_ready());
+                Ok::<_, ()>(())
+            })).unwrap();
+            checkout
+        };
+        let (first, second, mut third) = (start(), start(), start());
+        assert_eq!((dials.get(), pool.state.lock().unwrap().open), (1, 1));
+        drop(first);
+        drop(second);
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+        core.run(future::lazy(|| {
+            assert!(third.poll().unwrap().is_not_ready());
+            Ok::<_, ()>(())
+        })).unwrap();
+        assert_eq!((dials.get(), pool.state.lock().unwrap().open), (2, 1));
+        drop(third);
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+    }
+}
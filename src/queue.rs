@@ -0,0 +1,745 @@
+//! A durable queue of outbound mail
+//!
+//! Messages are accepted into a `Spool`, a directory on disk, and delivered
+//! from there by a `Worker` through a `Mailer`. Recipients that fail
+//! transiently are tried again later, until the message expires. Recipients
+//! that are rejected for good, or expire, are passed to a `BounceHandler`.
+//!
+//! Every message is stored as two files: the body in `<id>.eml`, and the
+//! envelope in `<id>.env`. The envelope is written last, and replaced by
+//! renaming, so a message is only picked up once it is complete. Files left
+//! behind by an interrupted write are removed by the `Worker` once they are
+//! an hour old. Disk access blocks the event loop.
+
+use error::{SmtpError};
+use futures::{future, stream, Future, Stream};
+use futures::future::{Loop};
+use rand;
+use request::{Mailbox};
+use response::{Response};
+use retry::{delay, should_retry};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::{Handle, Timeout};
+use super::{DeliveryReport, Mailer, RecipientStatus, RetryPolicy};
+
+
+/// A message in the spool
+#[derive(Clone,Debug)]
+pub struct QueuedMessage {
+    /// The identifier of the message within the spool
+    pub id: String,
+    /// The address to send bounces to
+    pub return_path: Mailbox,
+    /// The recipients that have yet to be delivered to
+    pub recipients: Vec<Mailbox>,
+    /// When the message was accepted into the spool
+    pub created: SystemTime,
+    /// The number of delivery attempts so far
+    pub attempts: u32,
+    /// When the next delivery attempt is due
+    pub next_attempt: SystemTime,
+}
+
+
+/// A directory of messages waiting for delivery
+///
+/// Only one `Worker` should process a spool at a time.
+#[derive(Clone,Debug)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    /// Open the spool in a directory, creating the directory if necessary.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> IoResult<Spool> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Spool { dir: dir })
+    }
+
+    /// Accept a message for delivery.
+    ///
+    /// The message is written to disk before this returns, and is due for
+    /// delivery immediately.
+    pub fn enqueue(&self, return_path: Mailbox, recipients: Vec<Mailbox>, body: &[u8])
+            -> IoResult<QueuedMessage> {
+        let now = SystemTime::now();
+        let (id, file) = self.create_body(|| format!("{:016x}{:08x}", as_secs(now), rand::random::<u32>()))?;
+        write_synced(file, body)?;
+        let message = QueuedMessage {
+            id: id,
+            return_path: return_path,
+            recipients: recipients,
+            created: now,
+            attempts: 0,
+            next_attempt: now,
+        };
+        self.save(&message)?;
+        Ok(message)
+    }
+
+    /// List the messages in the spool, oldest first.
+    ///
+    /// Envelopes that can't be read are skipped.
+    pub fn messages(&self) -> IoResult<Vec<QueuedMessage>> {
+        let mut messages = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "env") {
+                continue;
+            }
+            match read_envelope(&path) {
+                Ok(message) => messages.push(message),
+                Err(err) => warn!("skipping envelope {}: {}", path.display(), err),
+            }
+        }
+        messages.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+        Ok(messages)
+    }
+
+    /// Read the body of a message.
+    pub fn body(&self, id: &str) -> IoResult<Vec<u8>> {
+        let mut body = vec![];
+        File::open(self.path(id, "eml"))?.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// Remove a message from the spool.
+    pub fn remove(&self, id: &str) -> IoResult<()> {
+        fs::remove_file(self.path(id, "env"))?;
+        sync_dir(&self.dir)?;
+        fs::remove_file(self.path(id, "eml"))
+    }
+
+    /// Remove the files of messages that were never completely written,
+    /// if they were last modified at least `min_age` ago.
+    ///
+    /// Returns the number of files removed.
+    pub fn remove_orphans(&self, min_age: Duration) -> IoResult<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let is_orphan = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("tmp") => true,
+                Some("eml") => !path.with_extension("env").exists(),
+                _ => false,
+            };
+            let modified = entry.metadata()?.modified()?;
+            if is_orphan && now.duration_since(modified).map_or(false, |age| age >= min_age) {
+                debug!("removing orphaned spool file {}", path.display());
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Write the envelope of a message, replacing any previous version.
+    fn save(&self, message: &QueuedMessage) -> IoResult<()> {
+        let tmp = self.path(&message.id, "tmp");
+        write_synced(File::create(&tmp)?, format_envelope(message).as_bytes())?;
+        fs::rename(&tmp, self.path(&message.id, "env"))?;
+        // Also makes the body durable, which is created before the envelope.
+        sync_dir(&self.dir)
+    }
+
+    /// Create the body file of a new message, with the first id from
+    /// `new_id` that isn't taken.
+    fn create_body<F>(&self, mut new_id: F) -> IoResult<(String, File)>
+            where F: FnMut() -> String {
+        loop {
+            let id = new_id();
+            match OpenOptions::new().write(true).create_new(true).open(self.path(&id, "eml")) {
+                Ok(file) => return Ok((id, file)),
+                Err(ref err) if err.kind() == IoErrorKind::AlreadyExists => {},
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn path(&self, id: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, ext))
+    }
+}
+
+
+/// Why delivery to a recipient failed for good
+#[derive(Clone,Debug)]
+pub enum BounceReason {
+    /// The server rejected the recipient, or the message
+    Rejected(Response),
+    /// Delivery failed with an error that is not retried
+    Failed(String),
+    /// The message expired, or ran out of attempts
+    Expired,
+}
+
+/// A recipient that could not be delivered to
+#[derive(Clone,Debug)]
+pub struct BouncedRecipient {
+    /// The recipient address
+    pub recipient: Mailbox,
+    /// Why delivery failed
+    pub reason: BounceReason,
+}
+
+/// Generates bounce notifications for failed recipients
+pub trait BounceHandler {
+    /// Called when delivery to some recipients of a message has failed.
+    ///
+    /// This is called before the spool is updated, so it may be called again
+    /// for the same recipients if the process stops in between. The message
+    /// still lists the failed recipients. A message with a null return path
+    /// should not be bounced.
+    fn bounce(&self, message: &QueuedMessage, body: &[u8], recipients: Vec<BouncedRecipient>);
+}
+
+
+struct WorkerParams {
+    spool: Spool,
+    mailer: Mailer,
+    retry_policy: RetryPolicy,
+    lifetime: Duration,
+    poll_interval: Duration,
+    concurrency: usize,
+    bounce_handler: Option<Arc<BounceHandler + Send + Sync>>,
+}
+
+
+/// Delivers the messages in a spool.
+///
+/// A `Worker` is created using a `WorkerBuilder`.
+#[derive(Clone)]
+pub struct Worker(Arc<WorkerParams>);
+
+impl Worker {
+    /// Alias for `WorkerBuilder::new(spool, mailer)`.
+    pub fn builder(spool: Spool, mailer: Mailer) -> WorkerBuilder {
+        WorkerBuilder::new(spool, mailer)
+    }
+
+    /// Attempt delivery of every message that is due, once.
+    ///
+    /// Messages are delivered concurrently, up to the concurrency limit.
+    /// Errors for single messages are logged; this only fails if the spool
+    /// can't be read.
+    pub fn process(&self, handle: &Handle) -> Box<Future<Item = (), Error = IoError>> {
+        if let Err(err) = self.0.spool.remove_orphans(Duration::from_secs(60 * 60)) {
+            warn!("failed to remove orphaned spool files: {}", err);
+        }
+        let messages = match self.0.spool.messages() {
+            Ok(messages) => messages,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let now = SystemTime::now();
+        let worker = self.clone();
+        let handle = handle.clone();
+        let lifetime = self.0.lifetime;
+        let due = messages.into_iter()
+            .filter(|message| is_expired(message, lifetime, now) || message.next_attempt <= now)
+            .collect::<Vec<_>>();
+        let deliveries = due.into_iter()
+            .map(move |message| {
+                // Nothing is started until there is room.
+                let worker = worker.clone();
+                let handle = handle.clone();
+                future::lazy(move || {
+                    if is_expired(&message, lifetime, now) {
+                        Box::new(future::result(worker.expire(message)))
+                    } else {
+                        worker.deliver(message, &handle)
+                    }
+                })
+                .or_else(|err| {
+                    warn!("failed to process queued message: {}", err);
+                    Ok(())
+                })
+            });
+        Box::new(stream::iter_ok(deliveries)
+            .buffer_unordered(self.0.concurrency)
+            .for_each(|()| Ok(())))
+    }
+
+    /// Process the spool every poll interval, forever.
+    pub fn run(&self, handle: &Handle) -> Box<Future<Item = (), Error = IoError>> {
+        let worker = self.clone();
+        let handle = handle.clone();
+        Box::new(future::loop_fn((), move |()| {
+            let poll_interval = worker.0.poll_interval;
+            let handle = handle.clone();
+            worker.process(&handle)
+                .or_else(|err| {
+                    warn!("failed to read spool: {}", err);
+                    Ok(())
+                })
+                .and_then(move |()| Timeout::new(poll_interval, &handle))
+                .flatten()
+                .map(Loop::Continue)
+        }))
+    }
+
+    fn deliver(&self, message: QueuedMessage, handle: &Handle) -> Box<Future<Item = (), Error = IoError>> {
+        let body = match self.0.spool.body(&message.id) {
+            Ok(body) => body,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let worker = self.clone();
+        let send = self.0.mailer.send(message.return_path.clone(), message.recipients.clone(),
+                                      body.clone(), handle);
+        Box::new(send.then(move |result| worker.settle(message, &body, result)))
+    }
+
+    /// Update the spool with the result of a delivery attempt.
+    fn settle(&self, mut message: QueuedMessage, body: &[u8], result: Result<DeliveryReport, SmtpError>)
+            -> IoResult<()> {
+        let policy = &self.0.retry_policy;
+        let mut pending = vec![];
+        let mut bounced = vec![];
+        let statuses = match result {
+            Ok(report) => report.recipients,
+            Err(SmtpError::NoRecipients(statuses)) => statuses,
+            Err(err) => {
+                debug!("delivery of queued message {} failed: {}", message.id, err);
+                if should_retry(policy, &err) {
+                    pending = message.recipients.clone();
+                } else {
+                    let reason = match err.response() {
+                        Some(response) => BounceReason::Rejected(response.clone()),
+                        None => BounceReason::Failed(err.to_string()),
+                    };
+                    bounced = bounce_all(&message, &reason);
+                }
+                vec![]
+            },
+        };
+        for RecipientStatus { recipient, response } in statuses {
+            if response.code.severity.is_positive() {
+                continue;
+            } else if policy.severities.contains(&response.code.severity) {
+                pending.push(recipient);
+            } else {
+                bounced.push(BouncedRecipient {
+                    recipient: recipient,
+                    reason: BounceReason::Rejected(response),
+                });
+            }
+        }
+
+        message.attempts += 1;
+        if !pending.is_empty() && message.attempts >= policy.max_attempts {
+            bounced.extend(pending.drain(..).map(|recipient| BouncedRecipient {
+                recipient: recipient,
+                reason: BounceReason::Expired,
+            }));
+        }
+
+        self.bounce(&message, body, bounced);
+        if pending.is_empty() {
+            self.0.spool.remove(&message.id)
+        } else {
+            message.recipients = pending;
+            message.next_attempt = SystemTime::now() + delay(policy, message.attempts);
+            self.0.spool.save(&message)
+        }
+    }
+
+    /// Bounce all recipients of an expired message.
+    fn expire(&self, message: QueuedMessage) -> IoResult<()> {
+        let body = self.0.spool.body(&message.id)?;
+        let bounced = bounce_all(&message, &BounceReason::Expired);
+        self.bounce(&message, &body, bounced);
+        self.0.spool.remove(&message.id)
+    }
+
+    fn bounce(&self, message: &QueuedMessage, body: &[u8], recipients: Vec<BouncedRecipient>) {
+        if recipients.is_empty() {
+            return;
+        }
+        match self.0.bounce_handler {
+            Some(ref handler) => handler.bounce(message, body, recipients),
+            None => {
+                for bounced in recipients {
+                    warn!("dropping queued message {} for {}: {:?}",
+                          message.id, bounced.recipient, bounced.reason);
+                }
+            },
+        }
+    }
+}
+
+
+/// Builder for a `Worker` instance.
+pub struct WorkerBuilder {
+    spool: Spool,
+    mailer: Mailer,
+    retry_policy: RetryPolicy,
+    lifetime: Duration,
+    poll_interval: Duration,
+    concurrency: usize,
+    bounce_handler: Option<Arc<BounceHandler + Send + Sync>>,
+}
+
+impl WorkerBuilder {
+    /// Create a builder.
+    pub fn new(spool: Spool, mailer: Mailer) -> Self {
+        WorkerBuilder {
+            spool: spool,
+            mailer: mailer,
+            retry_policy: RetryPolicy {
+                max_attempts: 1000,
+                initial_delay: Duration::from_secs(5 * 60),
+                multiplier: 2.0,
+                max_delay: Duration::from_secs(60 * 60),
+                jitter: 0.1,
+                ..RetryPolicy::default()
+            },
+            lifetime: Duration::from_secs(5 * 24 * 60 * 60),
+            poll_interval: Duration::from_secs(60),
+            concurrency: 16,
+            bounce_handler: None,
+        }
+    }
+
+    /// Set when to try again after a failed attempt.
+    ///
+    /// This is separate from any retry policy of the `Mailer`, which retries
+    /// within a single attempt. By default, the delay starts at 5 minutes,
+    /// and doubles up to an hour, and attempts are effectively limited only
+    /// by the lifetime of messages.
+    ///
+    /// Building fails if the maximum number of attempts is 0.
+    pub fn set_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Set how long a message may stay in the spool.
+    ///
+    /// By default, this is 5 days, following RFC 5321, section 4.5.4.1.
+    pub fn set_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Set how often `run` looks for messages that are due.
+    ///
+    /// By default, this is every minute.
+    pub fn set_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Set how many messages are delivered at the same time.
+    ///
+    /// By default, this is 16. Building fails if the limit is 0.
+    pub fn set_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Set the handler for recipients that could not be delivered to.
+    ///
+    /// By default, failed recipients are only logged.
+    pub fn set_bounce_handler<H>(mut self, handler: H) -> Self
+            where H: BounceHandler + Send + Sync + 'static {
+        self.bounce_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Transform this builder into a `Worker`.
+    pub fn build(self) -> IoResult<Worker> {
+        if self.retry_policy.max_attempts == 0 {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "retry policy must allow at least one attempt"));
+        }
+        if self.concurrency == 0 {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "worker must deliver at least one message at a time"));
+        }
+        Ok(Worker(Arc::new(WorkerParams {
+            spool: self.spool,
+            mailer: self.mailer,
+            retry_policy: self.retry_policy,
+            lifetime: self.lifetime,
+            poll_interval: self.poll_interval,
+            concurrency: self.concurrency,
+            bounce_handler: self.bounce_handler,
+        })))
+    }
+}
+
+
+fn is_expired(message: &QueuedMessage, lifetime: Duration, now: SystemTime) -> bool {
+    now.duration_since(message.created).map_or(false, |age| age >= lifetime)
+}
+
+fn bounce_all(message: &QueuedMessage, reason: &BounceReason) -> Vec<BouncedRecipient> {
+    message.recipients.iter()
+        .map(|recipient| BouncedRecipient {
+            recipient: recipient.clone(),
+            reason: reason.clone(),
+        })
+        .collect()
+}
+
+fn write_synced(mut file: File, data: &[u8]) -> IoResult<()> {
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Make new, renamed and removed files in a directory durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> IoResult<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> IoResult<()> {
+    Ok(())
+}
+
+fn as_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+fn format_envelope(message: &QueuedMessage) -> String {
+    let mut envelope = format!("return-path {}\ncreated {}\nattempts {}\nnext-attempt {}\n",
+                               message.return_path, as_secs(message.created),
+                               message.attempts, as_secs(message.next_attempt));
+    for recipient in &message.recipients {
+        envelope.push_str(&format!("recipient {}\n", recipient));
+    }
+    envelope
+}
+
+fn parse_envelope(id: &str, envelope: &str) -> IoResult<QueuedMessage> {
+    let invalid = |what: &str| IoError::new(IoErrorKind::InvalidData, format!("invalid {}", what));
+    let time = |value: &str| value.parse().map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    let mut return_path = None;
+    let mut created = None;
+    let mut attempts = None;
+    let mut next_attempt = None;
+    let mut recipients = vec![];
+    for line in envelope.lines() {
+        let mut parts = line.splitn(2, ' ');
+        let key = parts.next().unwrap_or("");
+        let value = parts.next().ok_or_else(|| invalid("envelope line"))?;
+        match key {
+            "return-path" => return_path = Some(value.parse().map_err(|_| invalid(key))?),
+            "created" => created = Some(time(value).map_err(|_| invalid(key))?),
+            "attempts" => attempts = Some(value.parse().map_err(|_| invalid(key))?),
+            "next-attempt" => next_attempt = Some(time(value).map_err(|_| invalid(key))?),
+            "recipient" => recipients.push(value.parse().map_err(|_| invalid(key))?),
+            _ => return Err(invalid("envelope line")),
+        }
+    }
+    Ok(QueuedMessage {
+        id: id.to_string(),
+        return_path: return_path.ok_or_else(|| invalid("return-path"))?,
+        recipients: recipients,
+        created: created.ok_or_else(|| invalid("created"))?,
+        attempts: attempts.ok_or_else(|| invalid("attempts"))?,
+        next_attempt: next_attempt.ok_or_else(|| invalid("next-attempt"))?,
+    })
+}
+
+fn read_envelope(path: &Path) -> IoResult<QueuedMessage> {
+    let id = path.file_stem().and_then(|stem| stem.to_str())
+        .ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "invalid message id"))?;
+    let mut envelope = String::new();
+    File::open(path)?.read_to_string(&mut envelope)?;
+    parse_envelope(id, &envelope)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use error::{SmtpError};
+    use futures::{Future, Stream};
+    use queue::{BounceHandler, BounceReason, BouncedRecipient, QueuedMessage, Spool, Worker,
+                format_envelope, is_expired, parse_envelope};
+    use request::{Mailbox};
+    use std::env;
+    use std::fs;
+    use std::io::{ErrorKind as IoErrorKind};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+    use super::super::{Mailer, RetryPolicy};
+    use tests::{rejected, report, serve, statuses};
+    use tokio_core::reactor::{Core, Interval, Timeout};
+
+    struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl BounceHandler for Recorder {
+        fn bounce(&self, _: &QueuedMessage, _: &[u8], recipients: Vec<BouncedRecipient>) {
+            let mut log = self.0.lock().unwrap();
+            for BouncedRecipient { recipient, reason } in recipients {
+                let reason = match reason {
+                    BounceReason::Rejected(response) => response.code.to_string(),
+                    BounceReason::Failed(_) => "failed".to_string(),
+                    BounceReason::Expired => "expired".to_string(),
+                };
+                log.push((recipient.to_string(), reason));
+            }
+        }
+    }
+
+    #[test]
+    fn test() {
+        for input in vec![
+            "return-path <john@example.test>\ncreated 1500000000\nattempts 0\nnext-attempt 1500000000\n\
+             recipient <alice@example.test>\nrecipient <bob@example.test>\n",
+            "return-path <>\ncreated 1500000000\nattempts 3\nnext-attempt 1500000600\n",
+        ] {
+            let message = parse_envelope("id", input).unwrap();
+            assert_eq!(format_envelope(&message), input);
+        }
+        for input in vec![
+            "created 1500000000\nattempts 0\nnext-attempt 1500000000\n",
+            "return-path <>\ncreated soon\nattempts 0\nnext-attempt 1500000000\n",
+            "return-path <>\ncreated 1500000000\nattempts 0\nnext-attempt 1500000000\ncolor blue\n",
+        ] {
+            assert!(parse_envelope("id", input).is_err());
+        }
+
+        let dir = env::temp_dir().join(format!("tokio-smtp-spool-test-{}", ::rand::random::<u32>()));
+        let spool = Spool::open(&dir).unwrap();
+        let message = spool.enqueue("john@example.test".parse().unwrap(),
+                                    vec!["alice@example.test".parse().unwrap()],
+                                    b"hi\r\n").unwrap();
+        let messages = spool.messages().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message.id);
+        assert_eq!(messages[0].recipients[0].to_string(), "<alice@example.test>");
+        assert_eq!(spool.body(&message.id).unwrap(), b"hi\r\n");
+
+        // Taken ids are skipped, without touching the other message.
+        let mut ids = vec![message.id.clone(), "new".to_string()].into_iter();
+        let (id, _) = spool.create_body(|| ids.next().unwrap()).unwrap();
+        assert_eq!(id, "new");
+        assert_eq!(spool.body(&message.id).unwrap(), b"hi\r\n");
+        fs::remove_file(dir.join("new.eml")).unwrap();
+        spool.remove(&message.id).unwrap();
+        assert!(spool.messages().unwrap().is_empty());
+
+        // Leftovers of interrupted writes are removed once old enough.
+        let message = spool.enqueue("john@example.test".parse().unwrap(),
+                                    vec!["alice@example.test".parse().unwrap()],
+                                    b"hi\r\n").unwrap();
+        fs::write(dir.join("orphan.eml"), b"hi\r\n").unwrap();
+        fs::write(dir.join(format!("{}.tmp", message.id)), b"").unwrap();
+        assert_eq!(spool.remove_orphans(Duration::from_secs(60)).unwrap(), 0);
+        assert_eq!(spool.remove_orphans(Duration::from_secs(0)).unwrap(), 2);
+        assert_eq!(spool.messages().unwrap().len(), 1);
+        assert_eq!(spool.body(&message.id).unwrap(), b"hi\r\n");
+        spool.remove(&message.id).unwrap();
+
+        // Settle attempts, and check what is bounced and what stays queued.
+        let log = Arc::new(Mutex::new(vec![]));
+        let worker = Worker::builder(spool.clone(), Mailer::builder("127.0.0.1:25".to_string()).build().unwrap())
+            .set_retry_policy(RetryPolicy { max_attempts: 2, jitter: 0.0, ..RetryPolicy::default() })
+            .set_lifetime(Duration::from_secs(60))
+            .set_bounce_handler(Recorder(log.clone()))
+            .build().unwrap();
+        let recipients = vec!["a@example.test", "b@example.test", "c@example.test"].into_iter()
+            .map(|addr| addr.parse::<Mailbox>().unwrap())
+            .collect::<Vec<_>>();
+        let indexed = |codes: &[&'static str]| codes.iter().cloned().enumerate().collect::<Vec<_>>();
+        let statuses = |codes: &[&'static str]| statuses(&recipients, &indexed(codes));
+        let report = |codes: &[&'static str]| report(&recipients, &indexed(codes));
+
+        for (attempts, result, pending, bounced) in vec![
+            (0, report(&["250", "250", "250"]), None, vec![]),
+            (0, report(&["250", "450", "550"]), Some(vec!["<b@example.test>"]), vec![("<c@example.test>", "550")]),
+            (0, Err(SmtpError::NoRecipients(statuses(&["450", "550", "550"]))), Some(vec!["<a@example.test>"]),
+             vec![("<b@example.test>", "550"), ("<c@example.test>", "550")]),
+            (0, rejected("451"), Some(vec!["<a@example.test>", "<b@example.test>", "<c@example.test>"]), vec![]),
+            (0, Err(SmtpError::Timeout), Some(vec!["<a@example.test>", "<b@example.test>", "<c@example.test>"]), vec![]),
+            (0, rejected("554"), None,
+             vec![("<a@example.test>", "554"), ("<b@example.test>", "554"), ("<c@example.test>", "554")]),
+            (0, Err(SmtpError::AuthUnsupported), None,
+             vec![("<a@example.test>", "failed"), ("<b@example.test>", "failed"), ("<c@example.test>", "failed")]),
+            // The last attempt expires whatever is still pending.
+            (1, report(&["250", "450", "550"]), None, vec![("<c@example.test>", "550"), ("<b@example.test>", "expired")]),
+            (1, rejected("451"), None,
+             vec![("<a@example.test>", "expired"), ("<b@example.test>", "expired"), ("<c@example.test>", "expired")]),
+        ] {
+            let mut message = spool.enqueue("john@example.test".parse().unwrap(), recipients.clone(), b"hi\r\n").unwrap();
+            message.attempts = attempts;
+            worker.settle(message.clone(), b"hi\r\n", result).unwrap();
+            let queued = spool.messages().unwrap().into_iter()
+                .find(|queued| queued.id == message.id);
+            assert_eq!(queued.as_ref().map(|queued| queued.recipients.iter()
+                .map(|recipient| recipient.to_string())
+                .collect::<Vec<_>>()),
+                pending.map(|pending| pending.into_iter().map(|addr| addr.to_string()).collect()));
+            if let Some(queued) = queued {
+                assert_eq!(queued.attempts, attempts + 1);
+                assert!(queued.next_attempt > SystemTime::now());
+                spool.remove(&queued.id).unwrap();
+            }
+            let bounced = bounced.into_iter()
+                .map(|(addr, reason)| (addr.to_string(), reason.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(log.lock().unwrap().drain(..).collect::<Vec<_>>(), bounced);
+        }
+
+        let mut message = spool.enqueue("john@example.test".parse().unwrap(), recipients.clone(), b"hi\r\n").unwrap();
+        assert!(!is_expired(&message, Duration::from_secs(60), SystemTime::now()));
+        message.created -= Duration::from_secs(60);
+        assert!(is_expired(&message, Duration::from_secs(60), SystemTime::now()));
+        worker.expire(message).unwrap();
+        assert!(spool.messages().unwrap().is_empty());
+        assert_eq!(log.lock().unwrap().len(), 3);
+        assert!(log.lock().unwrap().iter().all(|&(_, ref reason)| reason == "expired"));
+
+        let mailer = Mailer::builder("127.0.0.1:25".to_string()).build().unwrap();
+        for &(max_attempts, concurrency) in &[(0, 16), (1000, 0)] {
+            let err = Worker::builder(spool.clone(), mailer.clone())
+                .set_retry_policy(RetryPolicy { max_attempts: max_attempts, ..RetryPolicy::default() })
+                .set_concurrency(concurrency)
+                .build().err().unwrap();
+            assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+        }
+
+        // Deliver through a local server, until the spool is empty.
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (addr, server_log) = serve("250 mx.test\r\n", &handle);
+        let worker = Worker::builder(spool.clone(), Mailer::builder(addr).build().unwrap())
+            .set_poll_interval(Duration::from_millis(10))
+            .set_concurrency(2)
+            .set_bounce_handler(Recorder(log.clone()))
+            .build().unwrap();
+        log.lock().unwrap().clear();
+        for &(return_path, recipients) in &[
+            ("john@example.test", &["alice@example.test", "bob@reject.test"][..]),
+            ("john@example.test", &["carol@example.test"][..]),
+            ("john@reject.test", &["dave@example.test"][..]),
+        ] {
+            let recipients = recipients.iter().map(|addr| addr.parse().unwrap()).collect();
+            spool.enqueue(return_path.parse().unwrap(), recipients, b"Hello\r\n").unwrap();
+        }
+        let deadline = Timeout::new(Duration::from_secs(5), &handle).unwrap();
+        let spool_check = spool.clone();
+        let emptied = Interval::new(Duration::from_millis(10), &handle).unwrap()
+            .take_while(move |()| Ok(!spool_check.messages().unwrap().is_empty()))
+            .for_each(|()| Ok(()));
+        core.run(worker.run(&handle).select2(emptied).select2(deadline)).ok().unwrap();
+        assert!(spool.messages().unwrap().is_empty());
+        let mut bounced = log.lock().unwrap().clone();
+        bounced.sort();
+        assert_eq!(bounced, vec![
+            ("<bob@reject.test>".to_string(), "550".to_string()),
+            ("<dave@example.test>".to_string(), "550".to_string()),
+        ]);
+        let server_log = server_log.borrow();
+        assert_eq!(server_log.iter().filter(|line| line.as_str() == "Hello\r\n").count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,18 +3,17 @@
 //! Aa request line consists of a command and arguments, but excludes the body
 //! (for e.g. `DATA`).
 
-// FIXME: Add parsing.
-
 use emailaddress::{EmailAddress, AddrError};
+use nom::{crlf, digit, IResult as NomResult};
 use std::io::{Error as IoError};
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::{FromStr};
+use std::str::{FromStr, from_utf8};
 use tokio_proto::streaming::pipeline::{Frame};
-use util::{XText};
+use util::{Utf8AddrXText, XText, decode_utf8_addr_xtext, decode_xtext, domain_to_ascii};
 
 
-/// Client identifier, the parameter to `EHLO`
+/// Client identifier, the parameter to `EHLO` or `HELO`
 #[derive(PartialEq,Eq,Clone,Debug)]
 pub enum ClientId {
     /// A fully-qualified domain name
@@ -27,6 +26,50 @@ pub enum ClientId {
     Other { tag: String, value: String },
 }
 
+impl ClientId {
+    pub fn parse(input: &[u8]) -> NomResult<&[u8], ClientId> {
+        parse_client_id(input)
+    }
+}
+
+impl FromStr for ClientId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ClientId, ()> {
+        // Address literals may be bracketed, but `Display` doesn't add them.
+        let inner = if s.starts_with('[') && s.ends_with(']') {
+            &s[1..s.len() - 1]
+        } else {
+            s
+        };
+
+        if inner.is_empty() {
+            return Err(());
+        }
+
+        if let Ok(addr) = inner.parse() {
+            return Ok(ClientId::Ipv4(addr));
+        }
+
+        match inner.find(':') {
+            Some(idx) => {
+                let (tag, value) = (&inner[..idx], &inner[idx + 1..]);
+                if tag.eq_ignore_ascii_case("IPv6") {
+                    value.parse()
+                        .map(ClientId::Ipv6)
+                        .map_err(|_| ())
+                } else {
+                    Ok(ClientId::Other {
+                        tag: tag.to_string(),
+                        value: value.to_string(),
+                    })
+                }
+            },
+            None => Ok(ClientId::Domain(inner.to_string())),
+        }
+    }
+}
+
 impl Display for ClientId {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
@@ -49,10 +92,46 @@ impl From<EmailAddress> for Mailbox {
     }
 }
 
+impl Mailbox {
+    /// Parse a mailbox in angle brackets, as found in a request line
+    pub fn parse(input: &[u8]) -> NomResult<&[u8], Mailbox> {
+        parse_mailbox(input)
+    }
+
+    /// Tells if the address is plain ASCII, and can be sent without
+    /// `SMTPUTF8`
+    pub fn is_ascii(&self) -> bool {
+        self.0.as_ref().map_or(true, |addr| addr.local.is_ascii() && addr.domain.is_ascii())
+    }
+
+    /// Convert the domain of the address to ASCII, using punycode.
+    ///
+    /// Returns `None` if the local part is not ASCII, in which case the
+    /// address can only be sent with `SMTPUTF8`, or if the domain can't be
+    /// converted.
+    pub fn to_ascii(&self) -> Option<Mailbox> {
+        match self.0 {
+            Some(ref addr) if !addr.local.is_ascii() => None,
+            Some(ref addr) => Some(Mailbox(Some(EmailAddress {
+                local: addr.local.clone(),
+                domain: domain_to_ascii(&addr.domain)?,
+            }))),
+            None => Some(Mailbox(None)),
+        }
+    }
+}
+
 impl FromStr for Mailbox {
     type Err = AddrError;
 
+    /// Parse a mailbox, with or without angle brackets.
     fn from_str(string: &str) -> Result<Mailbox, AddrError> {
+        let string = if string.starts_with('<') && string.ends_with('>') {
+            &string[1..string.len() - 1]
+        } else {
+            string
+        };
+
         if string.is_empty() {
             Ok(Mailbox(None))
         } else {
@@ -76,14 +155,64 @@ impl Display for Mailbox {
 pub enum MailParam {
     Body(MailBodyParam),
     Size(usize),
+    /// `SMTPUTF8` (RFC 6531)
+    SmtpUtf8,
+    /// `RET` (RFC 3461)
+    Ret(DsnReturn),
+    /// `ENVID` (RFC 3461)
+    EnvId(String),
     Other { keyword: String, value: Option<String> },
 }
 
+impl MailParam {
+    pub fn parse(input: &[u8]) -> NomResult<&[u8], MailParam> {
+        parse_mail_param(input)
+    }
+}
+
+impl FromStr for MailParam {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MailParam, ()> {
+        let (keyword, value) = split_param(s)?;
+        if keyword.eq_ignore_ascii_case("BODY") {
+            value.ok_or(())?
+                .parse()
+                .map(MailParam::Body)
+        } else if keyword.eq_ignore_ascii_case("SIZE") {
+            value.ok_or(())?
+                .parse()
+                .map(MailParam::Size)
+                .map_err(|_| ())
+        } else if keyword.eq_ignore_ascii_case("SMTPUTF8") {
+            match value {
+                None => Ok(MailParam::SmtpUtf8),
+                Some(_) => Err(()),
+            }
+        } else if keyword.eq_ignore_ascii_case("RET") {
+            value.ok_or(())?
+                .parse()
+                .map(MailParam::Ret)
+        } else if keyword.eq_ignore_ascii_case("ENVID") {
+            value.ok_or(())
+                .map(MailParam::EnvId)
+        } else {
+            Ok(MailParam::Other {
+                keyword: keyword.to_string(),
+                value: value,
+            })
+        }
+    }
+}
+
 impl Display for MailParam {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
             MailParam::Body(ref value) => write!(f, "BODY={}", value),
             MailParam::Size(size) => write!(f, "SIZE={}", size),
+            MailParam::SmtpUtf8 => f.write_str("SMTPUTF8"),
+            MailParam::Ret(ref value) => write!(f, "RET={}", value),
+            MailParam::EnvId(ref value) => write!(f, "ENVID={}", XText(value)),
             MailParam::Other { ref keyword, value: Some(ref value) } => {
                 write!(f, "{}={}", keyword, XText(value))
             },
@@ -102,6 +231,24 @@ pub enum MailBodyParam {
     SevenBit,
     /// `8BITMIME`
     EightBitMime,
+    /// `BINARYMIME`, which can only be sent with `BDAT` (RFC 3030)
+    BinaryMime,
+}
+
+impl FromStr for MailBodyParam {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MailBodyParam, ()> {
+        if s.eq_ignore_ascii_case("7BIT") {
+            Ok(MailBodyParam::SevenBit)
+        } else if s.eq_ignore_ascii_case("8BITMIME") {
+            Ok(MailBodyParam::EightBitMime)
+        } else if s.eq_ignore_ascii_case("BINARYMIME") {
+            Ok(MailBodyParam::BinaryMime)
+        } else {
+            Err(())
+        }
+    }
 }
 
 impl Display for MailBodyParam {
@@ -109,6 +256,95 @@ impl Display for MailBodyParam {
         match *self {
             MailBodyParam::SevenBit => f.write_str("7BIT"),
             MailBodyParam::EightBitMime => f.write_str("8BITMIME"),
+            MailBodyParam::BinaryMime => f.write_str("BINARYMIME"),
+        }
+    }
+}
+
+
+/// Values for the `RET` parameter to `MAIL FROM`
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum DsnReturn {
+    /// `FULL`, to return the full message in failure notifications
+    Full,
+    /// `HDRS`, to return only the headers
+    Headers,
+}
+
+impl FromStr for DsnReturn {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<DsnReturn, ()> {
+        if s.eq_ignore_ascii_case("FULL") {
+            Ok(DsnReturn::Full)
+        } else if s.eq_ignore_ascii_case("HDRS") {
+            Ok(DsnReturn::Headers)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl Display for DsnReturn {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            DsnReturn::Full => f.write_str("FULL"),
+            DsnReturn::Headers => f.write_str("HDRS"),
+        }
+    }
+}
+
+
+/// Values for the `NOTIFY` parameter to `RCPT TO`
+///
+/// Without any condition, this is `NEVER`.
+#[derive(PartialEq,Eq,Clone,Copy,Debug,Default)]
+pub struct DsnNotify {
+    /// `SUCCESS`
+    pub success: bool,
+    /// `FAILURE`
+    pub failure: bool,
+    /// `DELAY`
+    pub delay: bool,
+}
+
+impl FromStr for DsnNotify {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<DsnNotify, ()> {
+        let mut res = DsnNotify::default();
+        if s.eq_ignore_ascii_case("NEVER") {
+            return Ok(res);
+        }
+        for condition in s.split(',') {
+            let flag = if condition.eq_ignore_ascii_case("SUCCESS") {
+                &mut res.success
+            } else if condition.eq_ignore_ascii_case("FAILURE") {
+                &mut res.failure
+            } else if condition.eq_ignore_ascii_case("DELAY") {
+                &mut res.delay
+            } else {
+                return Err(());
+            };
+            *flag = true;
+        }
+        Ok(res)
+    }
+}
+
+impl Display for DsnNotify {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let conditions = [(self.success, "SUCCESS"), (self.failure, "FAILURE"), (self.delay, "DELAY")];
+        let mut conditions = conditions.iter().filter(|&&(set, _)| set).map(|&(_, name)| name);
+        match conditions.next() {
+            Some(first) => {
+                f.write_str(first)?;
+                for name in conditions {
+                    write!(f, ",{}", name)?;
+                }
+                Ok(())
+            },
+            None => f.write_str("NEVER"),
         }
     }
 }
@@ -117,12 +353,62 @@ impl Display for MailBodyParam {
 /// A `RCPT TO` extension parameter
 #[derive(PartialEq,Eq,Clone,Debug)]
 pub enum RcptParam {
+    /// `NOTIFY` (RFC 3461)
+    Notify(DsnNotify),
+    /// `ORCPT` (RFC 3461), with an address type such as `rfc822`
+    ///
+    /// Addresses of type `utf-8` are encoded as RFC 6533 describes.
+    OriginalRecipient { addr_type: String, addr: String },
     Other { keyword: String, value: Option<String> },
 }
 
+impl RcptParam {
+    pub fn parse(input: &[u8]) -> NomResult<&[u8], RcptParam> {
+        parse_rcpt_param(input)
+    }
+}
+
+impl FromStr for RcptParam {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<RcptParam, ()> {
+        let (keyword, value) = split_param(s)?;
+        if keyword.eq_ignore_ascii_case("NOTIFY") {
+            value.ok_or(())?
+                .parse()
+                .map(RcptParam::Notify)
+        } else if keyword.eq_ignore_ascii_case("ORCPT") {
+            let value = value.ok_or(())?;
+            let idx = value.find(';').ok_or(())?;
+            let addr_type = &value[..idx];
+            let addr = if addr_type.eq_ignore_ascii_case("utf-8") {
+                decode_utf8_addr_xtext(&value[idx + 1..])?
+            } else {
+                value[idx + 1..].to_string()
+            };
+            Ok(RcptParam::OriginalRecipient {
+                addr_type: addr_type.to_string(),
+                addr: addr,
+            })
+        } else {
+            Ok(RcptParam::Other {
+                keyword: keyword.to_string(),
+                value: value,
+            })
+        }
+    }
+}
+
 impl Display for RcptParam {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
+            RcptParam::Notify(ref value) => write!(f, "NOTIFY={}", value),
+            RcptParam::OriginalRecipient { ref addr_type, ref addr } if addr_type.eq_ignore_ascii_case("utf-8") => {
+                write!(f, "ORCPT={};{}", addr_type, Utf8AddrXText(addr))
+            },
+            RcptParam::OriginalRecipient { ref addr_type, ref addr } => {
+                write!(f, "ORCPT={};{}", addr_type, XText(addr))
+            },
             RcptParam::Other { ref keyword, value: Some(ref value) } => {
                 write!(f, "{}={}", keyword, XText(value))
             },
@@ -135,21 +421,59 @@ impl Display for RcptParam {
 
 
 /// Represents a complete request
+///
+/// `AuthResponse` is a line sent in reply to a `334` challenge during `AUTH`,
+/// and is not recognized by `Request::parse`.
 #[derive(PartialEq,Clone,Debug)]
 pub enum Request {
     Ehlo(ClientId),
+    Helo(ClientId),
     StartTls,
+    Auth { mechanism: String, initial_response: Option<String> },
+    AuthResponse(String),
     Mail { from: Mailbox, params: Vec<MailParam> },
     Rcpt { to: Mailbox, params: Vec<RcptParam> },
     Data,
+    /// A chunk of the message, of `size` bytes, sent after the command
+    /// (RFC 3030)
+    Bdat { size: usize, last: bool },
+    Rset,
+    Noop,
     Quit,
 }
 
+impl Request {
+    pub fn parse(input: &[u8]) -> NomResult<&[u8], Request> {
+        parse_request(input)
+    }
+}
+
+impl FromStr for Request {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Request, ()> {
+        match Request::parse(s.as_bytes()) {
+            NomResult::Done(&[], res) => Ok(res),
+            _ => Err(()),
+        }
+    }
+}
+
 impl Display for Request {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match *self {
             Request::Ehlo(ref id) => write!(f, "EHLO {}\r\n", id),
+            Request::Helo(ref id) => write!(f, "HELO {}\r\n", id),
             Request::StartTls => write!(f, "STARTTLS\r\n"),
+            Request::Auth { ref mechanism, initial_response: Some(ref response) } => {
+                write!(f, "AUTH {} {}\r\n", mechanism, response)
+            },
+            Request::Auth { ref mechanism, initial_response: None } => {
+                write!(f, "AUTH {}\r\n", mechanism)
+            },
+            Request::AuthResponse(ref response) => {
+                write!(f, "{}\r\n", response)
+            },
             Request::Mail { ref from, ref params } => {
                 write!(f, "MAIL FROM:{}", from)?;
                 for param in params {
@@ -167,6 +491,15 @@ impl Display for Request {
             Request::Data => {
                 f.write_str("DATA\r\n")
             },
+            Request::Bdat { size, last } => {
+                write!(f, "BDAT {}{}\r\n", size, if last { " LAST" } else { "" })
+            },
+            Request::Rset => {
+                f.write_str("RSET\r\n")
+            },
+            Request::Noop => {
+                f.write_str("NOOP\r\n")
+            },
             Request::Quit => {
                 f.write_str("QUIT\r\n")
             },
@@ -176,7 +509,10 @@ impl Display for Request {
 
 impl From<Request> for Frame<Request, Vec<u8>, IoError> {
     fn from(request: Request) -> Self {
-        let has_body = request == Request::Data;
+        let has_body = match request {
+            Request::Data | Request::Bdat { .. } => true,
+            _ => false,
+        };
         Frame::Message {
             message: request,
             body: has_body,
@@ -185,9 +521,126 @@ impl From<Request> for Frame<Request, Vec<u8>, IoError> {
 }
 
 
+// Parsers.
+//
+// Arguments are delimited by spaces, and a request must be a complete line,
+// including the final CRLF.
+
+fn is_arg_char(c: u8) -> bool {
+    c > b' ' && c != 0x7f
+}
+
+/// Split an extension parameter into keyword and decoded value.
+fn split_param(s: &str) -> Result<(&str, Option<String>), ()> {
+    let (keyword, value) = match s.find('=') {
+        Some(idx) => (&s[..idx], Some(decode_xtext(&s[idx + 1..])?)),
+        None => (s, None),
+    };
+    if keyword.is_empty() {
+        return Err(());
+    }
+    Ok((keyword, value))
+}
+
+/// Drop a source route, which RFC 5321 says to accept but ignore.
+fn strip_source_route(s: &str) -> Result<&str, ()> {
+    if s.starts_with('@') {
+        s.find(':').map(|idx| &s[idx + 1..]).ok_or(())
+    } else {
+        Ok(s)
+    }
+}
+
+named!(parse_client_id<ClientId>,
+    map_res!(
+        map_res!(take_while1!(is_arg_char), from_utf8),
+        ClientId::from_str
+    )
+);
+
+named!(parse_mailbox<Mailbox>,
+    map_res!(
+        map_res!(
+            delimited!(char!('<'), take_until!(">"), char!('>')),
+            from_utf8
+        ),
+        |s| strip_source_route(s).and_then(|s| {
+            Mailbox::from_str(s).map_err(|_| ())
+        })
+    )
+);
+
+named!(parse_mail_param<MailParam>,
+    map_res!(
+        map_res!(take_while1!(is_arg_char), from_utf8),
+        MailParam::from_str
+    )
+);
+
+named!(parse_rcpt_param<RcptParam>,
+    map_res!(
+        map_res!(take_while1!(is_arg_char), from_utf8),
+        RcptParam::from_str
+    )
+);
+
+named!(parse_request<Request>,
+    terminated!(
+        alt_complete!(
+            map!(
+                preceded!(tag_no_case!("EHLO "), parse_client_id),
+                Request::Ehlo
+            ) |
+            map!(
+                preceded!(tag_no_case!("HELO "), parse_client_id),
+                Request::Helo
+            ) |
+            value!(Request::StartTls, tag_no_case!("STARTTLS")) |
+            do_parse!(
+                tag_no_case!("AUTH ") >>
+                mechanism: map_res!(take_while1!(is_arg_char), from_utf8) >>
+                initial_response: opt!(preceded!(
+                    char!(' '),
+                    map_res!(take_while1!(is_arg_char), from_utf8)
+                )) >>
+                (Request::Auth {
+                    mechanism: mechanism.to_ascii_uppercase(),
+                    initial_response: initial_response.map(|s| s.to_string()),
+                })
+            ) |
+            do_parse!(
+                tag_no_case!("MAIL FROM:") >>
+                opt!(char!(' ')) >>
+                from: parse_mailbox >>
+                params: many0!(preceded!(char!(' '), parse_mail_param)) >>
+                (Request::Mail { from: from, params: params })
+            ) |
+            do_parse!(
+                tag_no_case!("RCPT TO:") >>
+                opt!(char!(' ')) >>
+                to: parse_mailbox >>
+                params: many0!(preceded!(char!(' '), parse_rcpt_param)) >>
+                (Request::Rcpt { to: to, params: params })
+            ) |
+            value!(Request::Data, tag_no_case!("DATA")) |
+            do_parse!(
+                tag_no_case!("BDAT ") >>
+                size: map_res!(map_res!(digit, from_utf8), usize::from_str) >>
+                last: opt!(complete!(tag_no_case!(" LAST"))) >>
+                (Request::Bdat { size: size, last: last.is_some() })
+            ) |
+            value!(Request::Rset, tag_no_case!("RSET")) |
+            value!(Request::Noop, tag_no_case!("NOOP")) |
+            value!(Request::Quit, tag_no_case!("QUIT"))
+        ),
+        crlf
+    )
+);
+
+
 #[cfg(test)]
 mod tests {
-    use request::{ClientId, MailBodyParam, MailParam, RcptParam, Request};
+    use request::{ClientId, DsnNotify, DsnReturn, Mailbox, MailBodyParam, MailParam, RcptParam, Request};
 
     #[test]
     fn test() {
@@ -204,10 +657,30 @@ mod tests {
                 ),
                 "EHLO 127.0.0.1\r\n",
             ),
+            (
+                Request::Helo(
+                    ClientId::Domain("foobar.example".to_string())
+                ),
+                "HELO foobar.example\r\n",
+            ),
             (
                 Request::StartTls,
                 "STARTTLS\r\n",
             ),
+            (
+                Request::Auth {
+                    mechanism: "PLAIN".to_string(),
+                    initial_response: Some("AGpvaG4Ac2VjcmV0".to_string()),
+                },
+                "AUTH PLAIN AGpvaG4Ac2VjcmV0\r\n",
+            ),
+            (
+                Request::Auth {
+                    mechanism: "LOGIN".to_string(),
+                    initial_response: None,
+                },
+                "AUTH LOGIN\r\n",
+            ),
             (
                 Request::Mail {
                     from: "".parse().unwrap(),
@@ -233,6 +706,29 @@ mod tests {
                 },
                 "MAIL FROM:<> BODY=8BITMIME SIZE=1024 X-FLAG X-VALUE=+2B\r\n",
             ),
+            (
+                Request::Mail {
+                    from: "john@example.test".parse().unwrap(),
+                    params: vec![
+                        MailParam::Ret(DsnReturn::Headers),
+                        MailParam::EnvId("QQ+314=".to_string()),
+                    ],
+                },
+                "MAIL FROM:<john@example.test> RET=HDRS ENVID=QQ+2B314+3D\r\n",
+            ),
+            (
+                Request::Rcpt {
+                    to: "alice@example.test".parse().unwrap(),
+                    params: vec![
+                        RcptParam::Notify(DsnNotify { success: true, failure: true, delay: false }),
+                        RcptParam::OriginalRecipient {
+                            addr_type: "rfc822".to_string(),
+                            addr: "a+b@example.test".to_string(),
+                        },
+                    ],
+                },
+                "RCPT TO:<alice@example.test> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;a+2Bb@example.test\r\n",
+            ),
             (
                 Request::Mail {
                     from: "john@example.test".parse().unwrap(),
@@ -270,16 +766,138 @@ mod tests {
                 },
                 "RCPT TO:<alice@example.test>\r\n",
             ),
+            (
+                Request::Mail {
+                    from: "j\u{f8}rn@b\u{fc}cher.example".parse().unwrap(),
+                    params: vec![MailParam::SmtpUtf8],
+                },
+                "MAIL FROM:<j\u{f8}rn@b\u{fc}cher.example> SMTPUTF8\r\n",
+            ),
             (
                 Request::Data,
                 "DATA\r\n",
             ),
+            (
+                Request::Bdat { size: 1024, last: false },
+                "BDAT 1024\r\n",
+            ),
+            (
+                Request::Bdat { size: 0, last: true },
+                "BDAT 0 LAST\r\n",
+            ),
+            (
+                Request::Rset,
+                "RSET\r\n",
+            ),
+            (
+                Request::Noop,
+                "NOOP\r\n",
+            ),
             (
                 Request::Quit,
                 "QUIT\r\n",
             ),
         ] {
             assert_eq!(input.to_string(), expect);
+            assert_eq!(expect.parse(), Ok(input));
+        }
+
+        for (input, expect) in vec![
+            (
+                "ehlo [IPv6:::1]\r\n",
+                Request::Ehlo(
+                    ClientId::Ipv6("::1".parse().unwrap())
+                ),
+            ),
+            (
+                "MAIL FROM: <john@example.test> BODY=7BIT\r\n",
+                Request::Mail {
+                    from: "john@example.test".parse().unwrap(),
+                    params: vec![
+                        MailParam::Body(MailBodyParam::SevenBit),
+                    ],
+                },
+            ),
+            (
+                "RCPT TO:<@relay.test:alice@example.test>\r\n",
+                Request::Rcpt {
+                    to: "alice@example.test".parse().unwrap(),
+                    params: vec![],
+                },
+            ),
+            (
+                "data\r\n",
+                Request::Data,
+            ),
+        ] {
+            assert_eq!(input.parse(), Ok(expect));
+        }
+
+        for input in vec![
+            "",
+            "EHLO\r\n",
+            "HELO\r\n",
+            "MAIL FROM:john@example.test\r\n",
+            "MAIL FROM:<> SIZE=\r\n",
+            "DATA",
+            "BDAT LAST\r\n",
+            "BDAT 10 NOW\r\n",
+            "HELP\r\n",
+        ] {
+            assert_eq!(input.parse::<Request>(), Err(()));
         }
+
+        assert_eq!(Request::AuthResponse("dGVzdA==".to_string()).to_string(), "dGVzdA==\r\n");
+
+        assert_eq!("[192.0.2.1]".parse(), Ok(ClientId::Ipv4("192.0.2.1".parse().unwrap())));
+        assert_eq!("IPv6:::1".parse(), Ok(ClientId::Ipv6("::1".parse().unwrap())));
+        assert_eq!("x-tag:value".parse(), Ok(ClientId::Other {
+            tag: "x-tag".to_string(),
+            value: "value".to_string(),
+        }));
+        assert_eq!("IPv6:bogus".parse::<ClientId>(), Err(()));
+
+        assert_eq!("<>".parse::<Mailbox>().unwrap(), Mailbox(None));
+        assert_eq!("<john@example.test>".parse::<Mailbox>().unwrap(),
+                   "john@example.test".parse::<Mailbox>().unwrap());
+
+        let long_label = format!("john@{}\u{fc}.example", "a".repeat(60));
+        for (input, is_ascii, expect) in vec![
+            ("<>", true, Some("<>")),
+            ("john@example.test", true, Some("<john@example.test>")),
+            ("john@b\u{fc}cher.example", false, Some("<john@xn--bcher-kva.example>")),
+            ("j\u{f8}rn@example.test", false, None),
+            (&long_label[..], false, None),
+        ] {
+            let mailbox = input.parse::<Mailbox>().unwrap();
+            assert_eq!(mailbox.is_ascii(), is_ascii);
+            assert_eq!(mailbox.to_ascii().map(|mailbox| mailbox.to_string()), expect.map(|s| s.to_string()));
+        }
+
+        assert_eq!("body=8bitmime".parse(), Ok(MailParam::Body(MailBodyParam::EightBitMime)));
+        assert_eq!("Body=BinaryMIME".parse(), Ok(MailParam::Body(MailBodyParam::BinaryMime)));
+        assert_eq!(MailParam::Body(MailBodyParam::BinaryMime).to_string(), "BODY=BINARYMIME");
+        assert_eq!("BODY=BINARY".parse::<MailParam>(), Err(()));
+        assert_eq!("SIZE=x".parse::<MailParam>(), Err(()));
+        assert_eq!("smtputf8".parse(), Ok(MailParam::SmtpUtf8));
+        assert_eq!("SMTPUTF8=yes".parse::<MailParam>(), Err(()));
+        assert_eq!("X-VALUE=a+3Db".parse(), Ok(RcptParam::Other {
+            keyword: "X-VALUE".to_string(),
+            value: Some("a=b".to_string()),
+        }));
+        assert_eq!("X-VALUE=a=b".parse::<RcptParam>(), Err(()));
+        assert_eq!("notify=never".parse(), Ok(RcptParam::Notify(DsnNotify::default())));
+        assert_eq!(RcptParam::Notify(DsnNotify::default()).to_string(), "NOTIFY=NEVER");
+        assert_eq!("NOTIFY=DELAY".parse(), Ok(RcptParam::Notify(DsnNotify { delay: true, ..DsnNotify::default() })));
+        assert_eq!("NOTIFY=NEVER,DELAY".parse::<RcptParam>(), Err(()));
+        assert_eq!("ORCPT=john@example.test".parse::<RcptParam>(), Err(()));
+        let orcpt = RcptParam::OriginalRecipient {
+            addr_type: "utf-8".to_string(),
+            addr: "bj\u{f8}rn+1@example.test".to_string(),
+        };
+        assert_eq!(orcpt.to_string(), "ORCPT=utf-8;bj\\x{F8}rn\\x{2B}1@example.test");
+        assert_eq!(orcpt.to_string().parse(), Ok(orcpt));
+        assert_eq!("ORCPT=utf-8;bj\\x{F8".parse::<RcptParam>(), Err(()));
+        assert_eq!("RET=NONE".parse::<MailParam>(), Err(()));
     }
 }
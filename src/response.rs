@@ -1,7 +1,8 @@
 //! Structures that model a response
 //!
 //! A response consists of a status code, and zero or more lines of text. This
-//! module does not derive any meaning from the response text.
+//! module does not derive any meaning from the response text, other than the
+//! enhanced status code (RFC 3463) it may start with.
 
 use nom::{crlf, ErrorKind as NomErrorKind, IResult as NomResult, Needed};
 use std::fmt::{Display, Formatter, Result as FmtResult};
@@ -154,6 +155,12 @@ impl Code {
     pub fn parse(input: &[u8]) -> NomResult<&[u8], Code> {
         parse_code(input)
     }
+
+    pub fn numeric(&self) -> u16 {
+        self.severity.numeric() as u16 * 100 +
+            self.category.numeric() as u16 * 10 +
+            self.detail.0 as u16
+    }
 }
 
 impl FromStr for Code {
@@ -174,6 +181,75 @@ impl Display for Code {
 }
 
 
+/// An enhanced status code (RFC 3463), e.g. `5.1.1`
+///
+/// The class matches the severity of the response code, and the subject and
+/// detail give the reason more precisely.
+#[derive(PartialEq,Eq,Copy,Clone,Debug)]
+pub struct EnhancedCode {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedCode {
+    /// Tells if the destination address doesn't exist, or is invalid
+    /// (`X.1.1` to `X.1.3`)
+    pub fn is_bad_destination(&self) -> bool {
+        self.subject == 1 && self.detail >= 1 && self.detail <= 3
+    }
+
+    /// Tells if the mailbox is full (`X.2.2`)
+    pub fn is_mailbox_full(&self) -> bool {
+        self.subject == 2 && self.detail == 2
+    }
+
+    /// Tells if the message was refused for security or policy reasons
+    /// (`X.7.*`), which includes requiring TLS
+    pub fn is_policy_rejection(&self) -> bool {
+        self.subject == 7
+    }
+
+    /// Tells if the server requires TLS (`X.7.10` or `X.7.11`)
+    pub fn is_tls_required(&self) -> bool {
+        self.subject == 7 && (self.detail == 10 || self.detail == 11)
+    }
+}
+
+impl FromStr for EnhancedCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EnhancedCode, ()> {
+        // The class is a single digit, the others up to three digits.
+        let mut parts = s.split('.').enumerate().map(|(idx, part)| {
+            let max_len = if idx == 0 { 1 } else { 3 };
+            if part.is_empty() || part.len() > max_len || !part.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(());
+            }
+            part.parse::<u16>().map_err(|_| ())
+        });
+        let code = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(class), Some(subject), Some(detail), None) => EnhancedCode {
+                class: class? as u8,
+                subject: subject?,
+                detail: detail?,
+            },
+            _ => return Err(()),
+        };
+        match code.class {
+            2 | 4 | 5 => Ok(code),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for EnhancedCode {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+
 /// Represents a complete response
 ///
 /// The message text is optional, and may be empty.
@@ -192,6 +268,23 @@ impl Response {
     pub fn first_word(&self) -> Option<&str> {
         self.text.get(0).and_then(|line| line.split_whitespace().next())
     }
+
+    /// The enhanced status code at the start of the message, if any
+    ///
+    /// Servers that advertise `ENHANCEDSTATUSCODES` (RFC 2034) start every
+    /// line with one. A code with a class that doesn't match the severity of
+    /// the response is ignored.
+    pub fn enhanced_code(&self) -> Option<EnhancedCode> {
+        self.first_word()
+            .and_then(|word| word.parse::<EnhancedCode>().ok())
+            .and_then(|code| {
+                if code.class == self.code.severity.numeric() {
+                    Some(code)
+                } else {
+                    None
+                }
+            })
+    }
 }
 
 impl FromStr for Response {
@@ -207,6 +300,10 @@ impl FromStr for Response {
 
 impl Display for Response {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.text.is_empty() {
+            return write!(f, "{}\r\n", &self.code);
+        }
+
         let last_idx = self.text.len() - 1;
         for (i, line) in self.text.iter().enumerate() {
             let delim = if i == last_idx { ' ' } else { '-' };
@@ -337,7 +434,7 @@ named!(parse_response<Response>,
 
 #[cfg(test)]
 mod tests {
-    use response::{Category, Code, Detail, Response, Severity};
+    use response::{Category, Code, Detail, EnhancedCode, Response, Severity};
 
     #[test]
     fn test() {
@@ -391,6 +488,7 @@ mod tests {
             assert_eq!(rest.len(), 0);
             assert_eq!(sub, expect);
             assert_eq!(expect.to_string(), normalized);
+            assert_eq!(expect.code.numeric().to_string(), &normalized[..3]);
         }
 
         for (word, input) in vec![
@@ -413,5 +511,45 @@ mod tests {
             };
             assert_eq!(sub.first_word(), word);
         }
+
+        for (input, expect) in vec![
+            ("550 5.1.1 No such user\r\n", Some("5.1.1")),
+            ("452-4.2.2 Mailbox full\r\n452 4.2.2 Try later\r\n", Some("4.2.2")),
+            ("250 2.0.0 Ok: queued as 12345\r\n", Some("2.0.0")),
+            ("530 5.7.10 Encryption needed\r\n", Some("5.7.10")),
+            // The class must match the severity.
+            ("550 4.1.1 No such user\r\n", None),
+            ("354 3.0.0 Go ahead\r\n", None),
+            ("550 No such user\r\n", None),
+            ("550 5.1 No such user\r\n", None),
+            ("550 5.1.1000 No such user\r\n", None),
+            ("550 5.1.x No such user\r\n", None),
+            ("550 005.1.1 No such user\r\n", None),
+            ("250 258.0.0 Ok\r\n", None),
+            ("550\r\n", None),
+        ] {
+            let response = input.parse::<Response>().unwrap();
+            let code = response.enhanced_code();
+            assert_eq!(code.map(|code| code.to_string()), expect.map(|s| s.to_string()));
+        }
+
+        for (input, bad_destination, mailbox_full, policy, tls) in vec![
+            ("5.1.1", true, false, false, false),
+            ("5.1.2", true, false, false, false),
+            ("5.1.6", false, false, false, false),
+            ("4.2.2", false, true, false, false),
+            ("5.7.1", false, false, true, false),
+            ("5.7.10", false, false, true, true),
+            ("5.7.11", false, false, true, true),
+        ] {
+            let code = input.parse::<EnhancedCode>().unwrap();
+            assert_eq!(code.is_bad_destination(), bad_destination);
+            assert_eq!(code.is_mailbox_full(), mailbox_full);
+            assert_eq!(code.is_policy_rejection(), policy);
+            assert_eq!(code.is_tls_required(), tls);
+        }
+        for input in &["258.1.1", "005.1.1", "5.1", "5.1.1.1", "3.0.0"] {
+            assert_eq!(input.parse::<EnhancedCode>(), Err(()));
+        }
     }
 }
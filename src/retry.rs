@@ -0,0 +1,273 @@
+//! Retrying sends of a `Mailer` that failed
+//!
+//! Every attempt only addresses the recipients that are still pending. The
+//! message body is buffered up front, so it can be sent again.
+
+use error::{SmtpError};
+use futures::{future, Future, Stream};
+use futures::future::{Loop};
+use rand::{self, Rng};
+use request::{Mailbox};
+use response::{Response, Severity};
+use std::time::{Duration};
+use tokio_core::reactor::{Handle, Timeout};
+use util::{detect_body_type, normalized_len};
+use super::{MessageInfo, DeliveryReport, IntoMailBody, Mailer, MailBody, RecipientStatus, RetryPolicy};
+
+
+struct State {
+    attempt: u32,
+    /// Indices into `recipients` of those still to be sent to
+    pending: Vec<usize>,
+    /// The latest response for every recipient
+    responses: Vec<Option<Response>>,
+    report: Option<DeliveryReport>,
+    error: Option<SmtpError>,
+}
+
+
+/// Send a message, retrying failures according to the policy.
+pub fn send(mailer: Mailer, policy: RetryPolicy, return_path: Mailbox, recipients: Vec<Mailbox>,
+            body: MailBody, message: MessageInfo, handle: &Handle)
+        -> Box<Future<Item = DeliveryReport, Error = SmtpError>> {
+    let handle = handle.clone();
+    Box::new(body.concat2().map_err(SmtpError::Body).and_then(move |body| {
+        // Now that the body is buffered, its type and size are known.
+        let message = MessageInfo {
+            body_type: Some(message.body_type.unwrap_or_else(|| detect_body_type(&body))),
+            size: Some(body.len()),
+            normalized_size: Some(normalized_len(&body)),
+            dsn: message.dsn,
+        };
+        let state = State {
+            attempt: 0,
+            pending: (0..recipients.len()).collect(),
+            responses: vec![None; recipients.len()],
+            report: None,
+            error: None,
+        };
+        future::loop_fn(state, move |mut state| {
+            let policy = policy.clone();
+            let recipients = recipients.clone();
+            let handle = handle.clone();
+            state.attempt += 1;
+            let to = state.pending.iter().map(|&idx| recipients[idx].clone()).collect();
+            let body = body.clone().into_mail_body(&handle);
+            mailer.send_raw(return_path.clone(), to, body, message.clone(), &handle).then(move |result| {
+                record(&policy, &mut state, result);
+                if state.pending.is_empty() || state.attempt >= policy.max_attempts {
+                    let result = finish(state, &recipients);
+                    return future::Either::A(future::result(result.map(Loop::Break)));
+                }
+
+                let delay = delay(&policy, state.attempt);
+                debug!("retrying {} recipient(s) in {:?}", state.pending.len(), delay);
+                future::Either::B(future::result(Timeout::new(delay, &handle))
+                    .flatten()
+                    .map_err(SmtpError::from)
+                    .map(move |_| Loop::Continue(state)))
+            })
+        })
+    }))
+}
+
+/// Update the state with the result of an attempt.
+fn record(policy: &RetryPolicy, state: &mut State, result: Result<DeliveryReport, SmtpError>) {
+    let statuses = match result {
+        Ok(ref report) => report.recipients.clone(),
+        Err(SmtpError::NoRecipients(ref statuses)) => statuses.clone(),
+        Err(ref err) => {
+            // The whole attempt failed, so all pending recipients share
+            // the fate of the error.
+            if let Some(response) = err.response() {
+                for &idx in &state.pending {
+                    state.responses[idx] = Some(response.clone());
+                }
+            }
+            if !should_retry(policy, err) {
+                state.pending.clear();
+            }
+            vec![]
+        },
+    };
+
+    if !statuses.is_empty() {
+        for (&idx, status) in state.pending.iter().zip(statuses) {
+            state.responses[idx] = Some(status.response);
+        }
+        let responses = &state.responses;
+        state.pending.retain(|&idx| {
+            responses[idx].as_ref().map_or(false, |response| {
+                policy.severities.contains(&response.code.severity)
+            })
+        });
+    }
+
+    match result {
+        Ok(report) => {
+            state.report = Some(report);
+            state.error = None;
+        },
+        Err(err) => state.error = Some(err),
+    }
+}
+
+/// Produce the final result, from the last successful attempt if any.
+fn finish(state: State, recipients: &[Mailbox]) -> Result<DeliveryReport, SmtpError> {
+    match state.report {
+        Some(mut report) => {
+            report.recipients = recipients.iter().zip(state.responses)
+                .filter_map(|(recipient, response)| response.map(|response| RecipientStatus {
+                    recipient: recipient.clone(),
+                    response: response,
+                }))
+                .collect();
+            Ok(report)
+        },
+        None => Err(state.error.expect("error of failed attempt")),
+    }
+}
+
+/// Tells if an error that failed an entire attempt is retried.
+pub fn should_retry(policy: &RetryPolicy, err: &SmtpError) -> bool {
+    let severity = match *err {
+        SmtpError::Io(_) | SmtpError::Timeout => Severity::TransientNegativeCompletion,
+        _ => match err.response() {
+            Some(response) => response.code.severity,
+            None => return false,
+        },
+    };
+    policy.severities.contains(&severity)
+}
+
+/// The delay before the given retry, starting from 1.
+pub fn delay(policy: &RetryPolicy, retry: u32) -> Duration {
+    let initial = as_secs_f64(policy.initial_delay);
+    let max = as_secs_f64(policy.max_delay);
+    let mut secs = (initial * policy.multiplier.powi(retry as i32 - 1)).min(max);
+    let jitter = policy.jitter.min(1.0);
+    if jitter > 0.0 {
+        secs *= 1.0 + rand::thread_rng().gen_range(-jitter, jitter);
+    }
+    Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+}
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+
+#[cfg(test)]
+mod tests {
+    use error::{SmtpError};
+    use request::{Mailbox, Request};
+    use response::{Severity};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use std::time::{Duration};
+    use super::{State, delay, finish, record, should_retry};
+    use super::super::{RetryPolicy};
+    use tests::{rejected, report, statuses};
+
+    #[test]
+    fn test() {
+        let mut policy = RetryPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(3),
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        for (retry, expect) in vec![
+            (1, Duration::from_millis(500)),
+            (2, Duration::from_secs(1)),
+            (3, Duration::from_secs(2)),
+            (4, Duration::from_secs(3)),
+        ] {
+            assert_eq!(delay(&policy, retry), expect);
+        }
+
+        policy.jitter = 0.5;
+        let jittered = delay(&policy, 2);
+        assert!(jittered >= Duration::from_millis(500) && jittered <= Duration::from_millis(1500));
+
+        for (err, expect) in vec![
+            (SmtpError::rejected(Request::Data, "451 Try later\r\n".parse().unwrap()), true),
+            (SmtpError::rejected(Request::Data, "554 No\r\n".parse().unwrap()), false),
+            (SmtpError::Io(IoError::new(IoErrorKind::ConnectionReset, "reset")), true),
+            (SmtpError::Timeout, true),
+            (SmtpError::AuthUnsupported, false),
+        ] {
+            assert_eq!(should_retry(&policy, &err), expect);
+        }
+
+        policy.severities = vec![Severity::PermanentNegativeCompletion];
+        assert!(should_retry(&policy, &SmtpError::rejected(Request::Data, "554 No\r\n".parse().unwrap())));
+        assert!(!should_retry(&policy, &SmtpError::Timeout));
+
+        // Feed the results of successive attempts, each addressing only the
+        // pending recipients, and check what remains pending.
+        let recipients = vec!["a@example.test", "b@example.test", "c@example.test"].into_iter()
+            .map(|addr| addr.parse::<Mailbox>().unwrap())
+            .collect::<Vec<_>>();
+        let statuses = |codes: &[(usize, &str)]| statuses(&recipients, codes);
+        let report = |codes: &[(usize, &str)]| report(&recipients, codes);
+
+        let policy = RetryPolicy::default();
+        for (attempts, expect) in vec![
+            // Partial acceptance, then the rest.
+            (vec![
+                (report(&[(0, "250"), (1, "450"), (2, "550")]), vec![1]),
+                (report(&[(1, "250")]), vec![]),
+            ], Ok(vec![(0, 250), (1, 250), (2, 550)])),
+            // No recipients accepted, then some.
+            (vec![
+                (Err(SmtpError::NoRecipients(statuses(&[(0, "450"), (1, "450"), (2, "550")]))), vec![0, 1]),
+                (report(&[(0, "250"), (1, "550")]), vec![]),
+            ], Ok(vec![(0, 250), (1, 550), (2, 550)])),
+            // Whole attempts failing after a partial success keep the report,
+            // with the latest response for the pending recipients.
+            (vec![
+                (report(&[(0, "250"), (1, "450"), (2, "451")]), vec![1, 2]),
+                (rejected("452"), vec![1, 2]),
+                (Err(SmtpError::Timeout), vec![1, 2]),
+            ], Ok(vec![(0, 250), (1, 452), (2, 452)])),
+            (vec![
+                (report(&[(0, "250"), (1, "450"), (2, "250")]), vec![1]),
+                (rejected("554"), vec![]),
+            ], Ok(vec![(0, 250), (1, 554), (2, 250)])),
+            // Without a success, the last error is reported.
+            (vec![
+                (rejected("451"), vec![0, 1, 2]),
+                (rejected("554"), vec![]),
+            ], Err("DATA rejected: 554 No")),
+            (vec![
+                (Err(SmtpError::NoRecipients(statuses(&[(0, "550"), (1, "550"), (2, "550")]))), vec![]),
+            ], Err("no recipients accepted")),
+        ] {
+            let mut state = State {
+                attempt: 0,
+                pending: (0..recipients.len()).collect(),
+                responses: vec![None; recipients.len()],
+                report: None,
+                error: None,
+            };
+            for (result, pending) in attempts {
+                state.attempt += 1;
+                record(&policy, &mut state, result);
+                assert_eq!(state.pending, pending);
+            }
+            match (finish(state, &recipients), expect) {
+                (Ok(report), Ok(expect)) => {
+                    let codes = report.recipients.iter()
+                        .map(|status| {
+                            let idx = recipients.iter().position(|r| *r == status.recipient).unwrap();
+                            (idx, status.response.code.numeric())
+                        })
+                        .collect::<Vec<_>>();
+                    assert_eq!(codes, expect);
+                },
+                (Err(err), Err(expect)) => assert_eq!(err.to_string(), expect),
+                (result, expect) => panic!("unexpected result {:?}, expected {:?}", result.map(|_| ()), expect),
+            }
+        }
+    }
+}
@@ -0,0 +1,627 @@
+//! The SMTP server implementation.
+//!
+//! The server is implemented as a [tokio-proto] streaming pipeline protocol.
+//! Every request is passed to the service, which answers with a single
+//! `Response`. The transport takes care of the parts of the protocol that
+//! don't fit this model: it sends the opening greeting, answers malformed
+//! lines and requests out of sequence, and sends the `354` intermediate reply
+//! to `DATA` before streaming the mail body to the service.
+//!
+//! The service is expected to answer `EHLO` with the extensions it supports.
+//! `STARTTLS` is not handled by the transport.
+//!
+//!  [tokio-proto]: https://docs.rs/tokio-proto/
+//!
+//! # Example
+//!
+//! ```no_run
+//! extern crate futures;
+//! extern crate tokio_proto;
+//! extern crate tokio_service;
+//! extern crate tokio_smtp;
+//!
+//! use futures::{future, Future, Stream};
+//! use std::io::{Error as IoError};
+//! use std::sync::{Arc};
+//! use tokio_proto::{TcpServer};
+//! use tokio_proto::streaming::{Body, Message};
+//! use tokio_service::{Service};
+//! use tokio_smtp::request::{Request as SmtpRequest};
+//! use tokio_smtp::response::{Response as SmtpResponse};
+//! use tokio_smtp::server::{ServerParams, ServerProto};
+//!
+//! struct Sink;
+//!
+//! impl Service for Sink {
+//!     type Request = Message<SmtpRequest, Body<Vec<u8>, IoError>>;
+//!     type Response = Message<SmtpResponse, Body<(), IoError>>;
+//!     type Error = IoError;
+//!     type Future = Box<Future<Item = Self::Response, Error = IoError>>;
+//!
+//!     fn call(&self, req: Self::Request) -> Self::Future {
+//!         let reply = |s: &str| Message::WithoutBody(s.parse().unwrap());
+//!         match req {
+//!             // Collect the body, and print it.
+//!             Message::WithBody(SmtpRequest::Data, body) => {
+//!                 Box::new(body.concat2().map(move |body| {
+//!                     println!("{}", String::from_utf8_lossy(&body));
+//!                     reply("250 OK\r\n")
+//!                 }))
+//!             },
+//!             Message::WithoutBody(SmtpRequest::Quit) => {
+//!                 Box::new(future::ok(reply("221 Bye\r\n")))
+//!             },
+//!             _ => Box::new(future::ok(reply("250 OK\r\n"))),
+//!         }
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let params = Arc::new(ServerParams {
+//!         id: "localhost".to_string(),
+//!     });
+//!     let addr = "127.0.0.1:2525".parse().unwrap();
+//!     TcpServer::new(ServerProto(params), addr)
+//!         .serve(|| Ok(Sink));
+//! }
+//! ```
+
+use bytes::{BytesMut};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::task;
+use nom::{IResult as NomResult};
+use request::{Request};
+use response::{Response};
+use std::cmp;
+use std::collections::{VecDeque};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::sync::{Arc};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, Decoder};
+use tokio_proto::streaming::pipeline::{Frame, ServerProto as TokioServerProto, Transport};
+use util::{Redacted};
+
+/// Maximum length of a request line, including CRLF (RFC 5321 4.5.3.1.4)
+const MAX_LINE_LEN: usize = 512;
+
+/// Size at which an incomplete body line is passed on as a partial chunk
+const MAX_CHUNK_LEN: usize = 8192;
+
+
+/// Parameters to use for the server
+pub struct ServerParams {
+    /// Server identifier, sent in the opening greeting
+    pub id: String,
+}
+
+
+/// The codec used to decode client requests and encode server responses
+///
+/// After decoding a `DATA` request, the codec decodes the mail body until the
+/// terminating `.` line, removing dot-stuffing. The body is produced as
+/// `Frame::Body` chunks, which always end on a line boundary unless a single
+/// line exceeds an internal limit.
+///
+/// A request line that is too long fails to decode once, and the rest of it
+/// is skipped.
+///
+/// `BDAT` (RFC 3030) is not supported. The request is decoded without a
+/// body, and its chunk is skipped.
+#[derive(Default)]
+pub struct ServerCodec {
+    in_body: bool,
+    line_start: bool,
+    body_done: bool,
+    skip: usize,
+    // Whether the rest of a request line that is too long is skipped.
+    discard_line: bool,
+}
+
+impl ServerCodec {
+    pub fn new() -> Self {
+        ServerCodec::default()
+    }
+
+    /// Return to decoding request lines, if we were expecting a mail body.
+    ///
+    /// Used when `DATA` is rejected, and no body will follow.
+    pub fn abort_body(&mut self) {
+        *self = ServerCodec::default();
+    }
+
+    fn decode_line(&mut self, buf: &mut BytesMut) -> IoResult<Option<Frame<Request, Vec<u8>, IoError>>> {
+        let idx = match find_crlf(buf.as_ref()) {
+            Some(idx) if self.discard_line => {
+                buf.split_to(idx + 2);
+                self.discard_line = false;
+                return self.decode_line(buf);
+            },
+            Some(idx) => idx,
+            None => {
+                if self.discard_line || buf.len() >= MAX_LINE_LEN {
+                    // Keep a possible CR, so we can find the end of the line.
+                    let len = buf.len().saturating_sub(1);
+                    buf.split_to(len);
+                    if !self.discard_line {
+                        self.discard_line = true;
+                        return Err(IoError::new(IoErrorKind::InvalidData, "request line too long"));
+                    }
+                }
+                return Ok(None);
+            },
+        };
+
+        let line = buf.split_to(idx + 2);
+        match Request::parse(line.as_ref()) {
+            NomResult::Done(&[], message @ Request::Bdat { .. }) => {
+                if let Request::Bdat { size, .. } = message {
+                    self.skip = size;
+                }
+                debug!("C: {:?}", Redacted(&message));
+                Ok(Some(Frame::Message { message: message, body: false }))
+            },
+            NomResult::Done(&[], message) => {
+                let body = message == Request::Data;
+                if body {
+                    self.in_body = true;
+                    self.line_start = true;
+                }
+                debug!("C: {:?}", Redacted(&message));
+                let frame = Frame::Message { message: message, body: body };
+                Ok(Some(frame))
+            },
+            _ => {
+                Err(IoError::new(IoErrorKind::InvalidData, "malformed request"))
+            },
+        }
+    }
+
+    fn decode_body(&mut self, buf: &mut BytesMut) -> IoResult<Option<Frame<Request, Vec<u8>, IoError>>> {
+        let mut chunk = Vec::new();
+        while !self.body_done {
+            let (data, is_line) = match find_crlf(buf.as_ref()) {
+                Some(idx) => (buf.split_to(idx + 2), true),
+                None => {
+                    // Hold on to short partial lines, so we can recognize
+                    // the terminator and dot-stuffing when the line ends.
+                    if buf.len() < MAX_CHUNK_LEN {
+                        break;
+                    }
+                    let mut len = buf.len();
+                    if buf[len - 1] == b'\r' {
+                        len -= 1;
+                    }
+                    (buf.split_to(len), false)
+                },
+            };
+
+            let mut start = 0;
+            if self.line_start {
+                if data.as_ref() == b".\r\n" {
+                    self.body_done = true;
+                    break;
+                }
+                if data[0] == b'.' {
+                    start = 1;
+                }
+            }
+            chunk.extend_from_slice(&data[start..]);
+            self.line_start = is_line;
+        }
+
+        if !chunk.is_empty() {
+            Ok(Some(Frame::Body { chunk: Some(chunk) }))
+        } else if self.body_done {
+            self.abort_body();
+            Ok(Some(Frame::Body { chunk: None }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = Frame<Request, Vec<u8>, IoError>;
+    type Error = IoError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> IoResult<Option<Self::Item>> {
+        if self.skip > 0 {
+            let len = cmp::min(self.skip, buf.len());
+            buf.split_to(len);
+            self.skip -= len;
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+        if self.in_body {
+            self.decode_body(buf)
+        } else {
+            self.decode_line(buf)
+        }
+    }
+}
+
+impl Encoder for ServerCodec {
+    type Item = Frame<Response, (), IoError>;
+    type Error = IoError;
+
+    fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> IoResult<()> {
+        debug!("S: {:?}", &frame);
+        match frame {
+            Frame::Message { message, .. } => {
+                buf.extend_from_slice(message.to_string().as_bytes());
+            },
+            Frame::Body { .. } => {},
+            Frame::Error { .. } => {
+                buf.extend_from_slice(b"451 Requested action aborted: local error in processing\r\n");
+            },
+        }
+        Ok(())
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|window| window == b"\r\n")
+}
+
+
+/// The kind of request a reply from the service is expected for.
+enum Pending {
+    /// `MAIL FROM`, starts a transaction
+    Mail,
+    /// `RCPT TO`, adds a recipient
+    Rcpt,
+    /// A request that ends the transaction, such as `DATA` or `RSET`
+    End,
+    /// Any other request
+    Other,
+    /// A reply generated by the transport, to be sent after earlier replies
+    Local(Response),
+}
+
+/// The transport used by `ServerProto`
+///
+/// Wraps an `Io` and a `ServerCodec`, and keeps track of the replies that are
+/// still outstanding, so that replies generated by the transport itself are
+/// sent in the right order when the client pipelines requests.
+pub struct ServerTransport<T> {
+    io: T,
+    codec: ServerCodec,
+    rd: BytesMut,
+    wr: BytesMut,
+    eof: bool,
+    pending: VecDeque<Pending>,
+    held_data: Option<Request>,
+    in_transaction: bool,
+    accepted_rcpts: usize,
+}
+
+impl<T> ServerTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    /// Wrap an `Io`, and queue the opening greeting.
+    pub fn new(io: T, params: &ServerParams) -> Self {
+        let mut transport = ServerTransport {
+            io: io,
+            codec: ServerCodec::new(),
+            rd: BytesMut::with_capacity(MAX_LINE_LEN),
+            wr: BytesMut::new(),
+            eof: false,
+            pending: VecDeque::new(),
+            held_data: None,
+            in_transaction: false,
+            accepted_rcpts: 0,
+        };
+        transport.reply(local_response(&format!("220 {} ESMTP\r\n", params.id)));
+        transport
+    }
+
+    fn write(&mut self, response: Response) {
+        // The codec never fails to encode.
+        let _ = self.codec.encode(Frame::Message { message: response, body: false }, &mut self.wr);
+    }
+
+    /// Send a reply generated by the transport, after any outstanding ones.
+    fn reply(&mut self, response: Response) {
+        if self.pending.is_empty() {
+            self.write(response);
+        } else {
+            self.pending.push_back(Pending::Local(response));
+        }
+    }
+
+    fn poll_data(&mut self, request: Request) -> Option<Frame<Request, Vec<u8>, IoError>> {
+        // Wait for replies to earlier recipients.
+        if !self.pending.is_empty() {
+            self.held_data = Some(request);
+            return None;
+        }
+
+        if !self.in_transaction {
+            if request == Request::Data {
+                self.codec.abort_body();
+            }
+            self.reply(local_response("503 Bad sequence of commands\r\n"));
+            return None;
+        }
+        if let Request::Bdat { .. } = request {
+            self.reply(local_response("500 Syntax error, command unrecognized\r\n"));
+            return None;
+        }
+        if self.accepted_rcpts == 0 {
+            self.codec.abort_body();
+            self.reply(local_response("554 No valid recipients\r\n"));
+            return None;
+        }
+
+        self.write(local_response("354 Start mail input; end with <CRLF>.<CRLF>\r\n"));
+        self.pending.push_back(Pending::End);
+        Some(Frame::Message { message: request, body: true })
+    }
+}
+
+impl<T> Stream for ServerTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type Item = Frame<Request, Vec<u8>, IoError>;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, IoError> {
+        loop {
+            if let Some(request) = self.held_data.take() {
+                match self.poll_data(request) {
+                    Some(frame) => return Ok(Async::Ready(Some(frame))),
+                    None if self.held_data.is_some() => return Ok(Async::NotReady),
+                    None => {},
+                }
+            }
+
+            match self.codec.decode(&mut self.rd) {
+                Ok(Some(Frame::Message { message, body: true })) |
+                Ok(Some(Frame::Message { message: message @ Request::Bdat { .. }, .. })) => {
+                    self.held_data = Some(message);
+                    continue;
+                },
+                Ok(Some(frame)) => {
+                    if let Frame::Message { ref message, .. } = frame {
+                        self.pending.push_back(match *message {
+                            Request::Mail { .. } => Pending::Mail,
+                            Request::Rcpt { .. } => Pending::Rcpt,
+                            Request::Ehlo(_) | Request::Helo(_) | Request::StartTls | Request::Rset => Pending::End,
+                            _ => Pending::Other,
+                        });
+                    }
+                    return Ok(Async::Ready(Some(frame)));
+                },
+                Ok(None) => {},
+                Err(ref err) if err.kind() == IoErrorKind::InvalidData => {
+                    self.reply(local_response("500 Syntax error, command unrecognized\r\n"));
+                    continue;
+                },
+                Err(err) => return Err(err),
+            }
+
+            if self.eof {
+                if self.codec.in_body {
+                    return Err(IoError::new(IoErrorKind::UnexpectedEof,
+                        "connection closed during mail body"));
+                }
+                return Ok(Async::Ready(None));
+            }
+
+            self.rd.reserve(MAX_LINE_LEN);
+            if try_ready!(AsyncRead::read_buf(&mut self.io, &mut self.rd)) == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<T> Sink for ServerTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type SinkItem = Frame<Response, (), IoError>;
+    type SinkError = IoError;
+
+    fn start_send(&mut self, frame: Self::SinkItem) -> StartSend<Self::SinkItem, IoError> {
+        if let Frame::Body { .. } = frame {
+            return Ok(AsyncSink::Ready);
+        }
+
+        let is_positive = match frame {
+            Frame::Message { ref message, .. } => message.code.severity.is_positive(),
+            _ => false,
+        };
+        match self.pending.pop_front() {
+            Some(Pending::Mail) => {
+                self.in_transaction = is_positive;
+                self.accepted_rcpts = 0;
+            },
+            Some(Pending::Rcpt) => if is_positive { self.accepted_rcpts += 1 },
+            Some(Pending::End) => {
+                self.in_transaction = false;
+                self.accepted_rcpts = 0;
+            },
+            Some(Pending::Other) => {},
+            _ => return Err(IoError::new(IoErrorKind::Other, "unexpected response")),
+        }
+        self.codec.encode(frame, &mut self.wr)?;
+
+        // Send replies of our own that were waiting on this one.
+        while let Some(&Pending::Local(_)) = self.pending.front() {
+            if let Some(Pending::Local(response)) = self.pending.pop_front() {
+                self.write(response);
+            }
+        }
+
+        // Make sure a held `DATA` request is looked at again.
+        if self.held_data.is_some() && self.pending.is_empty() {
+            task::current().notify();
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), IoError> {
+        while !self.wr.is_empty() {
+            let n = try_ready!(self.io.poll_write(self.wr.as_ref()));
+            if n == 0 {
+                return Err(IoError::new(IoErrorKind::WriteZero,
+                    "failed to write response to transport"));
+            }
+            self.wr.split_to(n);
+        }
+        self.io.poll_flush()
+    }
+
+    fn close(&mut self) -> Poll<(), IoError> {
+        try_ready!(self.poll_complete());
+        self.io.shutdown()
+    }
+}
+
+impl<T> Transport for ServerTransport<T>
+where T: AsyncRead + AsyncWrite + 'static
+{}
+
+fn local_response(s: &str) -> Response {
+    s.parse().expect("invalid local response")
+}
+
+
+/// The Tokio server protocol implementation
+///
+/// Implements an SMTP server using a streaming pipeline protocol.
+pub struct ServerProto(pub Arc<ServerParams>);
+
+impl<T> TokioServerProto<T> for ServerProto
+where T: AsyncRead + AsyncWrite + 'static
+{
+    type Request = Request;
+    type RequestBody = Vec<u8>;
+    type Response = Response;
+    type ResponseBody = ();
+    type Error = IoError;
+    type Transport = ServerTransport<T>;
+    type BindTransport = IoResult<Self::Transport>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(ServerTransport::new(io, &self.0))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BytesMut};
+    use futures::{future, Future, Stream};
+    use request::{Request};
+    use response::{Response};
+    use server::{ServerCodec, ServerParams, ServerProto};
+    use std::io::{Error as IoError, Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::{Arc};
+    use std::sync::mpsc::{channel};
+    use std::thread;
+    use tokio_core::net::{TcpListener};
+    use tokio_core::reactor::{Core};
+    use tokio_io::codec::{Decoder};
+    use tokio_proto::{BindServer};
+    use tokio_proto::streaming::{Body, Message};
+    use tokio_proto::streaming::pipeline::{Frame, StreamingPipeline};
+    use tokio_service::{Service};
+
+    /// A service that accepts everything
+    struct Accept;
+
+    impl Service for Accept {
+        type Request = Message<Request, Body<Vec<u8>, IoError>>;
+        type Response = Message<Response, Body<(), IoError>>;
+        type Error = IoError;
+        type Future = Box<Future<Item = Self::Response, Error = IoError>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            let reply = Message::WithoutBody("250 OK\r\n".parse().unwrap());
+            match req {
+                Message::WithBody(_, body) => Box::new(body.concat2().map(move |_| reply)),
+                Message::WithoutBody(_) => Box::new(future::ok(reply)),
+            }
+        }
+    }
+
+    #[test]
+    fn test() {
+        let long_line = vec![b'x'; 1100];
+        let mut input = b"MAIL FROM:<john@example.test>\r\nBOGUS\r\nDATA\r\n\
+            .first\r\nsecond\r\n..\r\n.\r\nBDAT 7 LAST\r\nBOGUS\r\n".to_vec();
+        input.extend_from_slice(&long_line);
+        input.extend_from_slice(b"\r\nQUIT\r\n");
+        let input = &input[..];
+
+        // Feed the input in pieces of every size, to test partial lines.
+        for size in 1..input.len() + 1 {
+            let mut codec = ServerCodec::new();
+            let mut buf = BytesMut::new();
+            let mut requests = vec![];
+            let mut body = vec![];
+            let mut errors = 0;
+            let mut body_ended = false;
+            for piece in input.chunks(size) {
+                buf.extend_from_slice(piece);
+                loop {
+                    match codec.decode(&mut buf) {
+                        Ok(Some(Frame::Message { message, .. })) => requests.push(message),
+                        Ok(Some(Frame::Body { chunk: Some(chunk) })) => body.extend(chunk),
+                        Ok(Some(Frame::Body { chunk: None })) => body_ended = true,
+                        Ok(Some(Frame::Error { .. })) => unreachable!(),
+                        Ok(None) => break,
+                        Err(_) => errors += 1,
+                    }
+                }
+            }
+
+            assert_eq!(requests, vec![
+                Request::Mail {
+                    from: "john@example.test".parse().unwrap(),
+                    params: vec![],
+                },
+                Request::Data,
+                Request::Bdat { size: 7, last: true },
+                Request::Quit,
+            ]);
+            // One for the bogus request, and one for the long line.
+            assert_eq!(errors, 2);
+            assert_eq!(body, b"first\r\nsecond\r\n.\r\n".to_vec());
+            assert!(body_ended);
+            assert_eq!(buf.len(), 0);
+        }
+
+        // Every request gets exactly one reply, in order.
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+            sender.send(listener.local_addr().unwrap()).unwrap();
+            let params = Arc::new(ServerParams { id: "mx.test".to_string() });
+            core.run(listener.incoming().for_each(move |(io, _)| {
+                BindServer::<StreamingPipeline<Body<(), IoError>>, _>
+                    ::bind_server(&ServerProto(params.clone()), &handle, io, Accept);
+                Ok(())
+            })).unwrap();
+        });
+        let mut io = TcpStream::connect(receiver.recv().unwrap()).unwrap();
+        let mut input = b"EHLO client.test\r\nDATA\r\nBDAT 3 LAST\r\nabc".to_vec();
+        input.extend_from_slice(&long_line);
+        input.extend_from_slice(b"\r\nNOOP\r\nMAIL FROM:<john@example.test>\r\nNOOP\r\n\
+            BDAT 3 LAST\r\nabcDATA\r\nRSET\r\nDATA\r\nQUIT\r\n");
+        io.write_all(&input).unwrap();
+        io.shutdown(Shutdown::Write).unwrap();
+        let mut output = String::new();
+        io.read_to_string(&mut output).unwrap();
+        let codes = output.lines().map(|line| &line[..3]).collect::<Vec<_>>();
+        assert_eq!(codes, vec![
+            "220", "250", "503", "503", "500", "250", "250", "250", "500", "554", "250", "503", "250",
+        ]);
+    }
+}
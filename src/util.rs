@@ -1,46 +1,633 @@
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use bytes::{BufMut, BytesMut};
+use error::{SmtpError};
+use futures::{future, Future, Sink, Stream};
+use futures::future::{Either, Loop};
+use std::cell::{RefCell};
+use request::{MailBodyParam, Request};
+use std::error::{Error as StdError};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult, Write};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{SocketAddr};
+use std::rc::{Rc};
+use std::time::{Duration};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_proto::streaming::{Body};
 
 
 /// Encode a string as xtext
+///
+/// Every byte outside printable ASCII, and `+` and `=`, is encoded as `+HH`.
 pub struct XText<'a>(pub &'a str);
 
 impl<'a> Display for XText<'a> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        let mut rest = self.0;
-        while let Some(idx) = rest.find(|c| {
-            c < '!' || c == '+' || c == '='
-        }) {
-            let (start, end) = rest.split_at(idx);
-            f.write_str(start)?;
-
-            let mut end_iter = end.char_indices();
-            let (_, c) = end_iter.next().expect("char");
-            write!(f, "+{:X}", c as u8)?;
-
-            if let Some((idx, _)) = end_iter.next() {
-                rest = &end[idx..];
+        for byte in self.0.bytes() {
+            if byte > b' ' && byte < 0x7f && byte != b'+' && byte != b'=' {
+                f.write_char(byte as char)?;
             } else {
-                rest = "";
+                write!(f, "+{:02X}", byte)?;
             }
         }
-        f.write_str(rest)
+        Ok(())
     }
 }
 
 
+/// Encode an address as `utf-8-addr-xtext` (RFC 6533, section 3)
+///
+/// Every character outside printable ASCII, and `\`, `+` and `=`, is
+/// encoded as `\x{HEX}`. The result is plain ASCII, and needs no further
+/// encoding as xtext.
+pub struct Utf8AddrXText<'a>(pub &'a str);
+
+impl<'a> Display for Utf8AddrXText<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        for c in self.0.chars() {
+            if c > ' ' && c < '\x7f' && c != '\\' && c != '+' && c != '=' {
+                f.write_char(c)?;
+            } else {
+                write!(f, "\\x{{{:02X}}}", c as u32)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode a `utf-8-addr-xtext` address
+pub fn decode_utf8_addr_xtext(input: &str) -> Result<String, ()> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(idx) = rest.find('\\') {
+        out.push_str(&rest[..idx]);
+        let escape = &rest[idx..];
+        if !escape.starts_with("\\x{") {
+            return Err(());
+        }
+        let end = escape.find('}').ok_or(())?;
+        let hex = &escape[3..end];
+        if hex.is_empty() || hex.len() > 6 {
+            return Err(());
+        }
+        let code = u32::from_str_radix(hex, 16).map_err(|_| ())?;
+        out.push(::std::char::from_u32(code).ok_or(())?);
+        rest = &escape[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+
+/// Format a request for logging, without authentication data
+pub struct Redacted<'a>(pub &'a Request);
+
+impl<'a> Debug for Redacted<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self.0 {
+            Request::Auth { ref mechanism, initial_response: Some(_) } => {
+                write!(f, "Auth {{ mechanism: {:?}, initial_response: <redacted> }}", mechanism)
+            },
+            Request::AuthResponse(_) => f.write_str("AuthResponse(<redacted>)"),
+            ref request => write!(f, "{:?}", request),
+        }
+    }
+}
+
+
+/// Decode an xtext string
+pub fn decode_xtext(input: &str) -> Result<String, ()> {
+    let mut bytes = input.bytes();
+    let mut out = Vec::with_capacity(input.len());
+    while let Some(byte) = bytes.next() {
+        if byte == b'+' {
+            let hi = bytes.next().and_then(hex_value).ok_or(())?;
+            let lo = bytes.next().and_then(hex_value).ok_or(())?;
+            out.push(hi << 4 | lo);
+        } else if byte < b'!' || byte == b'=' {
+            return Err(());
+        } else {
+            out.push(byte);
+        }
+    }
+    String::from_utf8(out).map_err(|_| ())
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+
+/// Reorder addresses to alternate between address families
+///
+/// The first address keeps its place, so its family is tried first. Order
+/// within each family is preserved.
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().map_or(false, |addr| addr.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_v6);
+
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+    out
+}
+
+
+/// Convert a domain to ASCII, encoding non-ASCII labels with punycode
+///
+/// The domain is lowercased, and ideographic full stops separate labels
+/// like `.` does, but labels are not otherwise mapped or normalized as IDNA
+/// (RFC 5891) requires. Returns `None` if a label is longer than 63 octets
+/// (RFC 1035, section 2.3.4), or can't be encoded.
+pub fn domain_to_ascii(domain: &str) -> Option<String> {
+    domain.to_lowercase()
+        .split(&['.', '\u{3002}', '\u{ff0e}', '\u{ff61}'][..])
+        .map(|label| {
+            let label = if label.is_ascii() {
+                label.to_string()
+            } else {
+                format!("xn--{}", punycode(label)?)
+            };
+            if label.len() > 63 {
+                None
+            } else {
+                Some(label)
+            }
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|labels| labels.join("."))
+}
+
+const PUNYCODE_BASE: u32 = 36;
+const PUNYCODE_TMIN: u32 = 1;
+const PUNYCODE_TMAX: u32 = 26;
+
+/// Encode a string with punycode (RFC 3492)
+///
+/// Returns `None` if the input is too long to encode.
+fn punycode(input: &str) -> Option<String> {
+    let chars = input.chars().map(|c| c as u32).collect::<Vec<_>>();
+    let mut out = input.chars().filter(|c| c.is_ascii()).collect::<String>();
+    let basic = out.len() as u32;
+    if basic > 0 {
+        out.push('-');
+    }
+
+    let mut handled = basic;
+    let mut n = 0x80;
+    let mut delta: u32 = 0;
+    let mut bias = 72;
+    while (handled as usize) < chars.len() {
+        let next = chars.iter().cloned().filter(|&c| c >= n).min().expect("unhandled char");
+        delta = (next - n).checked_mul(handled + 1)?.checked_add(delta)?;
+        n = next;
+        for &c in &chars {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            } else if c == n {
+                let mut q = delta;
+                let mut k = PUNYCODE_BASE;
+                loop {
+                    let t = if k <= bias {
+                        PUNYCODE_TMIN
+                    } else if k >= bias + PUNYCODE_TMAX {
+                        PUNYCODE_TMAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    out.push(punycode_digit(t + (q - t) % (PUNYCODE_BASE - t)));
+                    q = (q - t) / (PUNYCODE_BASE - t);
+                    k += PUNYCODE_BASE;
+                }
+                out.push(punycode_digit(q));
+                bias = punycode_adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+    Some(out)
+}
+
+fn punycode_adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / 700 } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > (PUNYCODE_BASE - PUNYCODE_TMIN) * PUNYCODE_TMAX / 2 {
+        delta /= PUNYCODE_BASE - PUNYCODE_TMIN;
+        k += PUNYCODE_BASE;
+    }
+    k + (PUNYCODE_BASE - PUNYCODE_TMIN + 1) * delta / (delta + 38)
+}
+
+fn punycode_digit(digit: u32) -> char {
+    if digit < 26 {
+        (b'a' + digit as u8) as char
+    } else {
+        (b'0' + (digit - 26) as u8) as char
+    }
+}
+
+
+/// Fail with `SmtpError::Timeout` if the future doesn't complete in time
+///
+/// Without a handle, no timeout is applied.
+pub fn with_timeout<F>(future: F, duration: Duration, handle: Option<&Handle>)
+    -> Box<Future<Item = F::Item, Error = F::Error>>
+where F: Future + 'static, F::Error: From<SmtpError> + From<IoError>
+{
+    let timeout = match handle.map(|handle| Timeout::new(duration, handle)) {
+        None => return Box::new(future),
+        Some(Ok(timeout)) => timeout,
+        Some(Err(err)) => return Box::new(future::err(err.into())),
+    };
+    Box::new(future.select2(timeout).then(|result| {
+        match result {
+            Ok(Either::A((item, _))) => Ok(item),
+            Ok(Either::B(_)) => Err(SmtpError::Timeout.into()),
+            Err(Either::A((err, _))) => Err(err),
+            Err(Either::B((err, _))) => Err(err.into()),
+        }
+    }))
+}
+
+
+/// Encodes a message body, as it is streamed in chunks
+///
+/// For `DATA`, lines starting with a `.` are escaped with another `.`
+/// (RFC 5321, section 4.5.2), including the first line, and the body is
+/// terminated with a final `CRLF`, if it doesn't end with one, and a line
+/// with a single `.`. Without normalization, a `.` after a bare `CR` or `LF`
+/// is escaped too, since some servers take those as line endings. For
+/// `BDAT`, only line endings are converted.
+pub struct BodyEncoder {
+    normalize: bool,
+    dot_stuff: bool,
+    // Whether the next byte starts a line.
+    line_start: bool,
+    // Whether the last byte was a `CR`. When normalizing, it is held back
+    // until the next byte is known.
+    after_cr: bool,
+    // Whether the last byte was a bare `LF`, which is kept as is.
+    after_lf: bool,
+}
+
+impl BodyEncoder {
+    pub fn new(normalize: bool, dot_stuff: bool) -> Self {
+        BodyEncoder {
+            normalize: normalize,
+            dot_stuff: dot_stuff,
+            line_start: true,
+            after_cr: false,
+            after_lf: false,
+        }
+    }
+
+    /// Reset the state for a new body.
+    pub fn start(&mut self) {
+        self.line_start = true;
+        self.after_cr = false;
+        self.after_lf = false;
+    }
+
+    pub fn encode(&mut self, chunk: &[u8], buf: &mut BytesMut) {
+        // Every byte results in at most 2 bytes, plus a held back `CR`.
+        buf.reserve(chunk.len() * 2 + 2);
+        for &byte in chunk {
+            let after_break = self.line_start || self.after_cr || self.after_lf;
+            self.after_lf = false;
+            if self.after_cr {
+                self.after_cr = false;
+                if byte == b'\n' {
+                    buf.put_slice(if self.normalize { b"\r\n" } else { b"\n" });
+                    self.line_start = true;
+                    continue;
+                }
+                if self.normalize {
+                    // A bare `CR` ends the line.
+                    buf.put_slice(b"\r\n");
+                    self.line_start = true;
+                }
+            }
+
+            match byte {
+                b'\r' => {
+                    self.after_cr = true;
+                    if !self.normalize {
+                        buf.put_u8(byte);
+                    }
+                    self.line_start = false;
+                    continue;
+                },
+                b'\n' if self.normalize => {
+                    // A bare `LF` ends the line.
+                    buf.put_slice(b"\r\n");
+                    self.line_start = true;
+                    continue;
+                },
+                b'\n' => {
+                    buf.put_u8(byte);
+                    self.after_lf = true;
+                    self.line_start = false;
+                    continue;
+                },
+                b'.' if after_break && self.dot_stuff => buf.put_u8(b'.'),
+                _ => {},
+            }
+            buf.put_u8(byte);
+            self.line_start = false;
+        }
+    }
+
+    /// Write a `CR` that was held back at the end of the body.
+    pub fn flush(&mut self, buf: &mut BytesMut) {
+        if self.normalize && self.after_cr {
+            buf.reserve(2);
+            buf.put_slice(b"\r\n");
+            self.after_cr = false;
+            self.line_start = true;
+        }
+    }
+
+    /// Terminate a `DATA` body, and reset the state.
+    pub fn finish(&mut self, buf: &mut BytesMut) {
+        self.flush(buf);
+        buf.reserve(5);
+        if self.after_cr {
+            buf.put_slice(b"\n");
+        } else if !self.line_start {
+            buf.put_slice(b"\r\n");
+        }
+        buf.put_slice(b".\r\n");
+        self.start();
+    }
+}
+
+
+/// Detect the `BODY` type a message needs
+///
+/// Bodies with any non-ASCII byte need `8BITMIME`, and so do bodies with
+/// `NUL` bytes or lines longer than 998 octets (RFC 5321, section
+/// 4.5.3.1.6). `BINARYMIME` is never detected, as it also depends on the
+/// MIME structure of the message; it must be given with a `TypedBody`.
+pub fn detect_body_type(body: &[u8]) -> MailBodyParam {
+    let mut eight_bit = false;
+    let mut line_len = 0;
+    for &byte in body {
+        match byte {
+            b'\r' | b'\n' => line_len = 0,
+            _ => {
+                line_len += 1;
+                eight_bit |= byte == 0 || byte >= 0x80 || line_len > 998;
+            },
+        }
+    }
+    if eight_bit {
+        MailBodyParam::EightBitMime
+    } else {
+        MailBodyParam::SevenBit
+    }
+}
+
+/// The error of `check_seven_bit`
+#[derive(Debug)]
+struct EightBitData;
+
+impl Display for EightBitData {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str("8-bit data in message body, but the server doesn't support 8BITMIME")
+    }
+}
+
+impl StdError for EightBitData {
+    fn description(&self) -> &str {
+        "8-bit data in message body"
+    }
+}
+
+/// The length of a message body once bare `CR` and `LF` are converted to
+/// `CRLF`, as `BodyEncoder` does
+pub fn normalized_len(body: &[u8]) -> usize {
+    let mut len = body.len();
+    for (idx, &byte) in body.iter().enumerate() {
+        match byte {
+            b'\r' if body.get(idx + 1) != Some(&b'\n') => len += 1,
+            b'\n' if idx == 0 || body[idx - 1] != b'\r' => len += 1,
+            _ => {},
+        }
+    }
+    len
+}
+
+/// Fail on a chunk of a 7-bit message body with non-ASCII bytes
+pub fn check_seven_bit(chunk: Vec<u8>) -> Result<Vec<u8>, IoError> {
+    if chunk.iter().any(|&byte| byte >= 0x80) {
+        Err(IoError::new(IoErrorKind::InvalidData, EightBitData))
+    } else {
+        Ok(chunk)
+    }
+}
+
+/// Tells if a body failed `check_seven_bit`
+pub fn is_eight_bit_error(err: &IoError) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<EightBitData>())
+}
+
+
+/// Where `abortable_body` stores the error of a failed body
+pub type BodyError = Rc<RefCell<Option<IoError>>>;
+
+/// Forward a message body, ending it early if it fails
+///
+/// On failure, the error is stored in the returned slot, and `abort` is
+/// called before the forwarded body ends, so the message can be discarded
+/// instead of terminated.
+pub fn abortable_body<S, F>(body: S, abort: F, handle: &Handle) -> (Body<Vec<u8>, IoError>, BodyError)
+where S: Stream<Item = Vec<u8>, Error = IoError> + 'static,
+      F: FnOnce() + 'static
+{
+    let (sender, out) = Body::pair();
+    let error = Rc::new(RefCell::new(None));
+    let slot = error.clone();
+    handle.spawn(future::loop_fn((body, sender, abort), move |(body, sender, abort)| {
+        let slot = slot.clone();
+        body.into_future().then(move |result| {
+            match result {
+                Ok((Some(chunk), body)) => Either::B(sender.send(Ok(chunk)).then(move |result| {
+                    match result {
+                        Ok(sender) => Ok(Loop::Continue((body, sender, abort))),
+                        // The body is no longer wanted.
+                        Err(_) => Ok(Loop::Break(())),
+                    }
+                })),
+                Ok((None, _)) => Either::A(future::ok(Loop::Break(()))),
+                Err((err, _)) => {
+                    *slot.borrow_mut() = Some(err);
+                    abort();
+                    Either::A(future::ok(Loop::Break(())))
+                },
+            }
+        })
+    }));
+    (out, error)
+}
+
+
 #[cfg(test)]
 mod tests {
-    use util::{XText};
+    use futures::{stream, Stream};
+    use request::{MailBodyParam, Request};
+    use std::cell::{Cell};
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use std::rc::{Rc};
+    use tokio_core::reactor::{Core};
+    use util::{Redacted, Utf8AddrXText, XText, abortable_body, check_seven_bit, decode_utf8_addr_xtext,
+               decode_xtext, detect_body_type, domain_to_ascii, interleave_families, is_eight_bit_error,
+               normalized_len};
 
     #[test]
     fn test() {
         for (input, expect) in vec![
             ("bjorn", "bjorn"),
-            ("bjørn", "bjørn"),
-            ("Ø+= ❤️‰", "Ø+2B+3D+20❤️‰"),
+            ("bjørn", "bj+C3+B8rn"),
+            ("Ø+= ❤", "+C3+98+2B+3D+20+E2+9D+A4"),
             ("+", "+2B"),
+            ("\t", "+09"),
+            ("\x7f", "+7F"),
         ] {
             assert_eq!(format!("{}", XText(input)), expect);
+            assert_eq!(decode_xtext(expect), Ok(input.to_string()));
+        }
+
+        for (input, expect) in vec![
+            ("bjorn@example.test", "bjorn@example.test"),
+            ("bjørn+1@bücher.example", "bj\\x{F8}rn\\x{2B}1@b\\x{FC}cher.example"),
+            ("a=\\ ❤", "a\\x{3D}\\x{5C}\\x{20}\\x{2764}"),
+        ] {
+            assert_eq!(format!("{}", Utf8AddrXText(input)), expect);
+            assert_eq!(decode_utf8_addr_xtext(expect), Ok(input.to_string()));
+        }
+        for input in &["\\", "\\x", "\\x{", "\\x{}", "\\x{G}", "\\x{D800}", "\\x{1234567}", "\\y{41}"] {
+            assert_eq!(decode_utf8_addr_xtext(input), Err(()));
+        }
+
+        for input in vec!["+", "+2", "+GG", "a=b", "a b"] {
+            assert_eq!(decode_xtext(input), Err(()));
+        }
+
+        for (input, expect) in vec![
+            (vec![], vec![]),
+            (vec!["[::1]:25", "[::2]:25", "[::3]:25", "127.0.0.1:25"],
+             vec!["[::1]:25", "127.0.0.1:25", "[::2]:25", "[::3]:25"]),
+            (vec!["127.0.0.1:25", "127.0.0.2:25", "[::1]:25", "[::2]:25"],
+             vec!["127.0.0.1:25", "[::1]:25", "127.0.0.2:25", "[::2]:25"]),
+        ] {
+            let input = input.into_iter().map(|s| s.parse().unwrap()).collect();
+            let expect = expect.into_iter().map(|s| s.parse().unwrap()).collect::<Vec<_>>();
+            assert_eq!(interleave_families(input), expect);
+        }
+
+        for (input, expect) in vec![
+            (Request::Auth { mechanism: "PLAIN".to_string(), initial_response: Some("AGpvaG4Ac2VjcmV0".to_string()) },
+             "Auth { mechanism: \"PLAIN\", initial_response: <redacted> }"),
+            (Request::Auth { mechanism: "LOGIN".to_string(), initial_response: None },
+             "Auth { mechanism: \"LOGIN\", initial_response: None }"),
+            (Request::AuthResponse("c2VjcmV0".to_string()), "AuthResponse(<redacted>)"),
+            (Request::Quit, "Quit"),
+        ] {
+            assert_eq!(format!("{:?}", Redacted(&input)), expect);
+        }
+
+        let long_line = vec![b'a'; 999];
+        for (input, expect) in vec![
+            (&b""[..], MailBodyParam::SevenBit),
+            (&b"Hello\r\nWorld\n"[..], MailBodyParam::SevenBit),
+            ("Hej, Bj\u{f8}rn\r\n".as_bytes(), MailBodyParam::EightBitMime),
+            (&b"a\0b"[..], MailBodyParam::EightBitMime),
+            (&long_line[..998], MailBodyParam::SevenBit),
+            (&long_line[..], MailBodyParam::EightBitMime),
+        ] {
+            assert_eq!(detect_body_type(input), expect);
+        }
+
+        for (input, expect) in vec![
+            (&b""[..], 0),
+            (b"a\r\nb", 4),
+            (b"a\nb\rc", 7),
+            (b"a\r\r\n", 5),
+            (b"\n\r", 4),
+        ] {
+            assert_eq!(normalized_len(input), expect);
+        }
+
+        assert!(check_seven_bit(b"plain\r\n".to_vec()).is_ok());
+        assert!(is_eight_bit_error(&check_seven_bit("Bj\u{f8}rn".as_bytes().to_vec()).unwrap_err()));
+        assert!(!is_eight_bit_error(&IoError::new(IoErrorKind::InvalidData, "8-bit data")));
+
+        for (input, expect) in vec![
+            ("example.test", "example.test"),
+            ("M\u{fc}nchen.example", "xn--mnchen-3ya.example"),
+            ("b\u{fc}cher.\u{fc}", "xn--bcher-kva.xn--tda"),
+            // From RFC 3492, section 7.1.
+            ("\u{4ed6}\u{4eec}\u{4e3a}\u{4ec0}\u{4e48}\u{4e0d}\u{8bf4}\u{4e2d}\u{6587}",
+             "xn--ihqwcrb4cv8a8dqg056pqjye"),
+            ("3\u{5e74}B\u{7d44}\u{91d1}\u{516b}\u{5148}\u{751f}", "xn--3b-ww4c5e180e575a65lsy2b"),
+        ] {
+            assert_eq!(domain_to_ascii(input), Some(expect.to_string()));
+        }
+        let long_label = "a".repeat(64);
+        for input in vec![
+            "B\u{fc}cher.Example",
+            "b\u{fc}cher\u{3002}example",
+            "b\u{fc}cher\u{ff0e}example",
+        ] {
+            assert_eq!(domain_to_ascii(input), Some("xn--bcher-kva.example".to_string()));
+        }
+        for input in vec![
+            format!("{}.example", long_label),
+            format!("{}.example", "\u{fc}".repeat(60)),
+            "\u{10ffff}".repeat(1000),
+        ] {
+            assert_eq!(domain_to_ascii(&input), None);
+        }
+        assert_eq!(domain_to_ascii(&format!("{}.example", &long_label[1..])),
+                   Some(format!("{}.example", &long_label[1..])));
+
+        // A failing body ends after the chunks before the failure, which is
+        // signalled before the end.
+        let mut core = Core::new().unwrap();
+        for (input, expect, aborts) in vec![
+            (vec![Ok(b"a".to_vec()), Ok(vec![]), Ok(b"b".to_vec())], vec![&b"a"[..], b"", b"b"], false),
+            (vec![Ok(b"a".to_vec()), Err("disk on fire"), Ok(b"b".to_vec())], vec![&b"a"[..]], true),
+            (vec![Err("disk on fire")], vec![], true),
+        ] {
+            let input = input.into_iter()
+                .map(|chunk| chunk.map_err(|err| IoError::new(IoErrorKind::Other, err)));
+            let aborted = Rc::new(Cell::new(false));
+            let flag = aborted.clone();
+            let (body, error) = abortable_body(stream::iter_result(input), move || flag.set(true),
+                                               &core.handle());
+            let flag = aborted.clone();
+            let chunks = core.run(body
+                .map(move |chunk| {
+                    assert!(!flag.get());
+                    chunk
+                })
+                .collect()).unwrap();
+            assert_eq!(chunks, expect);
+            assert_eq!(aborted.get(), aborts);
+            assert_eq!(error.borrow().as_ref().map(|err| err.to_string()),
+                       if aborts { Some("disk on fire".to_string()) } else { None });
         }
     }
 }